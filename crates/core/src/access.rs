@@ -0,0 +1,36 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Allow and deny lists applied to client addresses when a connection is accepted.
+///
+/// A client matching any `deny` entry is rejected. If `allow` is not empty, the client
+/// must also match one of its entries.
+#[derive(Clone, Debug, Default)]
+pub struct AccessControl {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessControl {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Clients without an address, e.g. on a Unix socket, are only allowed without an
+    /// allow list.
+    pub fn is_allowed_addr(&self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) => self.is_allowed(ip),
+            None => self.allow.is_empty(),
+        }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped addresses come from dual-stack listeners
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
@@ -0,0 +1,91 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::HeaderValue;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Credentials checked against the `Proxy-Authorization` header using the Basic scheme.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyAuth {
+    credentials: HashMap<String, String>,
+}
+
+impl ProxyAuth {
+    pub fn new(credentials: HashMap<String, String>) -> Self {
+        Self { credentials }
+    }
+
+    pub fn add_user(&mut self, username: String, password: String) {
+        self.credentials.insert(username, password);
+    }
+
+    /// Returns the authenticated username, or `None` if the header is missing or the
+    /// credentials do not match.
+    pub fn authenticate(&self, header: Option<&HeaderValue>) -> Option<String> {
+        let header = header?.to_str().ok()?;
+        let (scheme, encoded) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let decoded = STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+
+        let expected = self.credentials.get(username);
+        // compared for unknown users too, so the time taken doesn't tell them apart
+        let matches = password_matches(expected.map_or("", String::as_str), password);
+        (expected.is_some() && matches).then(|| username.to_owned())
+    }
+}
+
+/// Compares the digests in constant time, so the time taken reveals nothing of the password,
+/// not even its length.
+fn password_matches(expected: &str, password: &str) -> bool {
+    let (expected, password) = (Sha256::digest(expected), Sha256::digest(password));
+    expected
+        .iter()
+        .zip(password.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> ProxyAuth {
+        ProxyAuth::new(HashMap::from([("user".to_owned(), "secret".to_owned())]))
+    }
+
+    fn header(credentials: &str) -> HeaderValue {
+        HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(credentials))).unwrap()
+    }
+
+    #[test]
+    fn authenticates() {
+        let auth = auth();
+        assert_eq!(
+            auth.authenticate(Some(&header("user:secret"))),
+            Some("user".to_owned())
+        );
+        let lowercase = HeaderValue::from_str(&format!("basic {}", STANDARD.encode("user:secret")));
+        assert_eq!(
+            auth.authenticate(Some(&lowercase.unwrap())),
+            Some("user".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_credentials() {
+        let auth = auth();
+        assert_eq!(auth.authenticate(None), None);
+        assert_eq!(auth.authenticate(Some(&header("user:wrong"))), None);
+        assert_eq!(auth.authenticate(Some(&header("user:secret2"))), None);
+        assert_eq!(auth.authenticate(Some(&header("other:secret"))), None);
+        assert_eq!(auth.authenticate(Some(&header("user"))), None);
+        for malformed in ["Basic !!!", "Basic", "Bearer dXNlcjpzZWNyZXQ="] {
+            let malformed = HeaderValue::from_static(malformed);
+            assert_eq!(auth.authenticate(Some(&malformed)), None);
+        }
+    }
+}
@@ -93,9 +93,12 @@ impl CertificateAuthority {
         params.serial_number = Some(thread_rng().gen::<u64>());
         params.not_before = OffsetDateTime::now_utc().saturating_sub(1.days());
         params.not_after = OffsetDateTime::now_utc().saturating_add((CERT_TTL_DAYS as i64).days());
-        params
-            .subject_alt_names
-            .push(SanType::DnsName(server_name.to_string()));
+        // clients send no SNI for IP addresses, the CONNECT authority names the cert then
+        let san = match server_name.trim_matches(&['[', ']'][..]).parse() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(server_name.to_string()),
+        };
+        params.subject_alt_names.push(san);
         let mut distinguished_name = DistinguishedName::new();
         distinguished_name.push(DnType::CommonName, server_name);
         params.distinguished_name = distinguished_name;
@@ -142,6 +145,34 @@ impl CertificateAuthority {
             .with_cert_resolver(self);
         Arc::new(server_cfg)
     }
+
+    /// Like [`gen_server_config`](Self::gen_server_config), but clients without SNI get a
+    /// certificate for `default_name`.
+    pub(crate) fn gen_server_config_with_default(
+        self: Arc<Self>,
+        default_name: &str,
+    ) -> Arc<ServerConfig> {
+        let server_cfg = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(DefaultNameResolver {
+                ca: self,
+                default_name: default_name.to_owned(),
+            }));
+        Arc::new(server_cfg)
+    }
+}
+
+struct DefaultNameResolver {
+    ca: Arc<CertificateAuthority>,
+    default_name: String,
+}
+
+impl ResolvesServerCert for DefaultNameResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().unwrap_or(&self.default_name);
+        Some(self.ca.get_certified_key(name))
+    }
 }
 
 impl ResolvesServerCert for CertificateAuthority {
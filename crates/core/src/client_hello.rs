@@ -0,0 +1,418 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::{fmt::Write, io};
+
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXTENSION_EC_POINT_FORMATS: u16 = 0x000b;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXTENSION_ALPN: u16 = 0x0010;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 0x002b;
+const EXTENSION_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+
+/// The parsed TLS ClientHello of a client, with its JA3 and JA4 fingerprints.
+#[derive(Debug, Clone, Default)]
+pub struct ClientHello {
+    /// The legacy `client_version` field.
+    pub version: u16,
+    pub server_name: Option<String>,
+    pub alpn: Vec<Vec<u8>>,
+    pub supported_versions: Vec<u16>,
+    pub cipher_suites: Vec<u16>,
+    /// Extension types in the order sent.
+    pub extensions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+
+    /// MD5 of the JA3 string, see https://github.com/salesforce/ja3.
+    pub ja3: String,
+    /// See https://github.com/FoxIO-LLC/ja4.
+    pub ja4: String,
+}
+
+impl ClientHello {
+    /// Parse a ClientHello handshake message body, without type and length.
+    pub(crate) fn parse(body: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader(body);
+        let mut hello = ClientHello {
+            version: reader.u16()?,
+            ..Default::default()
+        };
+
+        // random, session id
+        reader.take(32)?;
+        reader.vec_u8()?;
+
+        let mut ciphers = reader.vec_u16()?;
+        while !ciphers.is_empty() {
+            hello.cipher_suites.push(ciphers.u16()?);
+        }
+
+        // compression methods
+        reader.vec_u8()?;
+
+        // extensions are optional before TLS 1.2
+        if !reader.is_empty() {
+            let mut extensions = reader.vec_u16()?;
+            while !extensions.is_empty() {
+                let typ = extensions.u16()?;
+                let mut data = extensions.vec_u16()?;
+                hello.extensions.push(typ);
+                hello.parse_extension(typ, &mut data)?;
+            }
+        }
+
+        hello.ja3 = hex(&Md5::digest(hello.ja3_string()));
+        hello.ja4 = hello.ja4_string();
+        Ok(hello)
+    }
+
+    fn parse_extension(&mut self, typ: u16, data: &mut Reader) -> io::Result<()> {
+        match typ {
+            EXTENSION_SERVER_NAME => {
+                let mut list = data.vec_u16()?;
+                while !list.is_empty() {
+                    const NAME_TYPE_HOST_NAME: u8 = 0;
+                    let name_typ = list.u8()?;
+                    let name = list.vec_u16()?;
+                    if name_typ == NAME_TYPE_HOST_NAME && self.server_name.is_none() {
+                        let name = String::from_utf8(name.0.to_vec())
+                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                        self.server_name = Some(name);
+                    }
+                }
+            }
+            EXTENSION_ALPN => {
+                let mut list = data.vec_u16()?;
+                while !list.is_empty() {
+                    self.alpn.push(list.vec_u8()?.0.to_vec());
+                }
+            }
+            EXTENSION_SUPPORTED_VERSIONS => {
+                let mut list = data.vec_u8()?;
+                while !list.is_empty() {
+                    self.supported_versions.push(list.u16()?);
+                }
+            }
+            EXTENSION_SUPPORTED_GROUPS => {
+                let mut list = data.vec_u16()?;
+                while !list.is_empty() {
+                    self.supported_groups.push(list.u16()?);
+                }
+            }
+            EXTENSION_EC_POINT_FORMATS => {
+                self.ec_point_formats = data.vec_u8()?.0.to_vec();
+            }
+            EXTENSION_SIGNATURE_ALGORITHMS => {
+                let mut list = data.vec_u16()?;
+                while !list.is_empty() {
+                    self.signature_algorithms.push(list.u16()?);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether the client sent an Encrypted Client Hello, its server name is then the public
+    /// name of the ECH provider. Clients also send GREASE ECH, which looks the same.
+    pub fn offers_ech(&self) -> bool {
+        self.extensions.contains(&EXTENSION_ENCRYPTED_CLIENT_HELLO)
+    }
+
+    /// `SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats`
+    pub fn ja3_string(&self) -> String {
+        fn join<T: ToString>(values: impl Iterator<Item = T>) -> String {
+            values.map(|v| v.to_string()).collect::<Vec<_>>().join("-")
+        }
+
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(not_grease(&self.cipher_suites)),
+            join(not_grease(&self.extensions)),
+            join(not_grease(&self.supported_groups)),
+            join(self.ec_point_formats.iter()),
+        )
+    }
+
+    fn ja4_string(&self) -> String {
+        let version = not_grease(&self.supported_versions)
+            .max()
+            .unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+        let sni = if self.extensions.contains(&EXTENSION_SERVER_NAME) {
+            'd'
+        } else {
+            'i'
+        };
+        let alpn = match self.alpn.first() {
+            Some(alpn) if !alpn.is_empty() => {
+                let (first, last) = (alpn[0], alpn[alpn.len() - 1]);
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                } else {
+                    let (first, last) = (format!("{first:02x}"), format!("{last:02x}"));
+                    format!("{}{}", &first[..1], &last[1..])
+                }
+            }
+            _ => "00".to_owned(),
+        };
+
+        let mut ciphers: Vec<u16> = not_grease(&self.cipher_suites).collect();
+        ciphers.sort_unstable();
+        let extensions_count = not_grease(&self.extensions).count();
+        let mut extensions: Vec<u16> = not_grease(&self.extensions)
+            .filter(|ext| *ext != EXTENSION_SERVER_NAME && *ext != EXTENSION_ALPN)
+            .collect();
+        extensions.sort_unstable();
+
+        let mut extensions_hash = join_hex(&extensions);
+        if !self.signature_algorithms.is_empty() {
+            extensions_hash.push('_');
+            extensions_hash.push_str(&join_hex(&self.signature_algorithms));
+        }
+
+        format!(
+            "t{version}{sni}{:02}{:02}{alpn}_{}_{}",
+            ciphers.len().min(99),
+            extensions_count.min(99),
+            truncated_sha256(&ciphers, &join_hex(&ciphers)),
+            truncated_sha256(&extensions, &extensions_hash),
+        )
+    }
+}
+
+/// GREASE values are random placeholders, see RFC 8701.
+fn not_grease(values: &[u16]) -> impl Iterator<Item = u16> + '_ {
+    values
+        .iter()
+        .copied()
+        .filter(|v| v & 0x0f0f != 0x0a0a || v >> 8 != v & 0xff)
+}
+
+fn join_hex(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{v:04x}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn truncated_sha256(values: &[u16], s: &str) -> String {
+    if values.is_empty() {
+        return "000000000000".to_owned();
+    }
+    let mut hash = hex(&Sha256::digest(s));
+    hash.truncate(12);
+    hash
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<Reader<'a>> {
+        if self.0.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated ClientHello",
+            ));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(Reader(taken))
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?.0[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?.0;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn vec_u8(&mut self) -> io::Result<Reader<'a>> {
+        let len = self.u8()?;
+        self.take(len.into())
+    }
+
+    fn vec_u16(&mut self) -> io::Result<Reader<'a>> {
+        let len = self.u16()?;
+        self.take(len.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sni_reader::{read_client_hello, HandshakeRecordReader};
+
+    /// A ClientHello record of `openssl s_client -servername example.com -alpn h2,http/1.1
+    /// -groups X25519:P-256` from OpenSSL 3.5.
+    const OPENSSL_RECORD: &[&str] = &[
+        "1603010145010001410303aa1ab24e241a8db993c82f0973db794b2ac1e3ae96625b01bd9597a70b96ba8720",
+        "fce5da43828e299277902b4903d3ca185c3d2ef6cf52ac0e4489b813459b5b85003c130213031301c02cc030",
+        "009fcca9cca8ccaac02bc02f009ec024c028006bc023c0270067c00ac0140039c009c0130033009d009c003d",
+        "003c0035002f010000bcff0100010000000010000e00000b6578616d706c652e636f6d000b00040300010200",
+        "0a00060004001d0017002300000010000e000c02683208687474702f312e310016000000170000000d003600",
+        "3409050906090404030503060308070808081a081b081c0809080a080b080408050806040105010601030303",
+        "010302040205020602002b00050403040303002d00020101003300260024001d0020aa40113ec6edba566ddd",
+        "a148dfcc18266ec673c93657a3ed51f3ac13f5be4e26",
+    ];
+
+    fn record() -> Vec<u8> {
+        let hex = OPENSSL_RECORD.concat();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    async fn read(record: &[u8]) -> io::Result<ClientHello> {
+        let reader = HandshakeRecordReader::new(record);
+        tokio::pin!(reader);
+        read_client_hello(reader).await
+    }
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    fn vec_u8(data: &[u8]) -> Vec<u8> {
+        [&[data.len() as u8][..], data].concat()
+    }
+
+    fn vec_u16(data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u16).to_be_bytes()[..], data].concat()
+    }
+
+    fn ext(typ: u16, data: &[u8]) -> Vec<u8> {
+        [&typ.to_be_bytes()[..], &vec_u16(data)].concat()
+    }
+
+    /// A ClientHello handshake body.
+    fn hello(version: u16, ciphers: &[u16], extensions: &[Vec<u8>]) -> Vec<u8> {
+        [
+            &version.to_be_bytes()[..],
+            &[0; 32],
+            &vec_u8(&[]),
+            &vec_u16(&u16s(ciphers)),
+            &vec_u8(&[0]),
+            &vec_u16(&extensions.concat()),
+        ]
+        .concat()
+    }
+
+    fn sni(host: &str) -> Vec<u8> {
+        ext(
+            0x0000,
+            &vec_u16(&[&[0][..], &vec_u16(host.as_bytes())].concat()),
+        )
+    }
+
+    #[tokio::test]
+    async fn openssl_fingerprints() {
+        let hello = read(&record()).await.unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, [b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert_eq!(
+            hello.ja3_string(),
+            "771,4866-4867-4865-49196-49200-159-52393-52392-52394-49195-49199-158-49188-49192-\
+             107-49187-49191-103-49162-49172-57-49161-49171-51-157-156-61-60-53-47,\
+             65281-0-11-10-35-16-22-23-13-43-45-51,29-23,0-1-2"
+        );
+        assert_eq!(hello.ja3, "3cccd0410cd2a6eb87bab586a6f51e73");
+        assert_eq!(hello.ja4, "t13d3012h2_1d37bd780c83_8e6e362c5eac");
+    }
+
+    /// The example of https://github.com/salesforce/ja3.
+    #[test]
+    fn ja3_published() {
+        let ciphers = [47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4];
+        let extensions = [
+            sni("example.com"),
+            ext(0x000a, &vec_u16(&u16s(&[23, 24, 25]))),
+            ext(0x000b, &vec_u8(&[0])),
+        ];
+        let hello = ClientHello::parse(&hello(0x0301, &ciphers, &extensions)).unwrap();
+        assert_eq!(
+            hello.ja3_string(),
+            "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0"
+        );
+        assert_eq!(hello.ja3, "ada70206e40642a3e4461f35503241d5");
+    }
+
+    /// The Chrome example of https://github.com/FoxIO-LLC/ja4, with GREASE values.
+    #[test]
+    fn ja4_published_with_grease() {
+        let ciphers = [
+            0x3a3a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+            0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+        ];
+        let signature_algorithms = [
+            0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+        ];
+        let alpn = vec_u16(&[vec_u8(b"h2"), vec_u8(b"http/1.1")].concat());
+        let extensions = [
+            ext(0x2a2a, &[]),
+            ext(0x0033, &[]),
+            sni("example.com"),
+            ext(0x0010, &alpn),
+            ext(0x000a, &vec_u16(&u16s(&[0x4a4a, 0x001d, 0x0017, 0x0018]))),
+            ext(0x000b, &vec_u8(&[0])),
+            ext(0x000d, &vec_u16(&u16s(&signature_algorithms))),
+            ext(0x002b, &vec_u8(&u16s(&[0x5a5a, 0x0304, 0x0303]))),
+            ext(0x0005, &[]),
+            ext(0x0012, &[]),
+            ext(0x0015, &[]),
+            ext(0x0017, &[]),
+            ext(0x001b, &[]),
+            ext(0x0023, &[]),
+            ext(0x002d, &[]),
+            ext(0x4469, &[]),
+            ext(0xff01, &[]),
+            ext(0x1a1a, &[]),
+        ];
+        let hello = ClientHello::parse(&hello(0x0303, &ciphers, &extensions)).unwrap();
+        assert_eq!(hello.ja4, "t13d1516h2_8daaf6152771_e5627efa2ab1");
+        assert!(!hello.ja3_string().contains("14906"));
+        assert!(!hello.ja3_string().contains("10794"));
+    }
+
+    #[tokio::test]
+    async fn truncated_record() {
+        let record = record();
+        for len in [3, 5, 40, record.len() - 1] {
+            let err = read(&record[..len]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{len}");
+        }
+    }
+
+    #[test]
+    fn truncated_extension() {
+        let extensions = [sni("example.com")];
+        let mut body = hello(0x0303, &[0x1301], &extensions);
+        body.truncate(body.len() - 3);
+        assert!(ClientHello::parse(&body).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    proxy_protocol::{write_header, ProxyProtocolVersion},
+    timeout::{KeepAlive, Timeouts},
+};
+use hyper::{
+    client::{
+        connect::dns::{GaiAddrs, GaiResolver, Name},
+        HttpConnector,
+    },
+    service::Service,
+    Uri,
+};
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use thiserror::Error;
+use tokio::net::TcpStream;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+tokio::task_local! {
+    /// The client an upstream request is made for, announced by PROXY protocol headers.
+    pub(crate) static CLIENT_ADDR: Option<SocketAddr>;
+}
+
+/// Connects to upstream servers over TCP, optionally sending a PROXY protocol header first.
+#[derive(Clone)]
+pub(crate) struct UpstreamConnector {
+    http: HttpConnector<Resolver>,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+impl UpstreamConnector {
+    pub fn new(
+        proxy_protocol: Option<ProxyProtocolVersion>,
+        timeouts: &Timeouts,
+        keep_alive: &KeepAlive,
+    ) -> Self {
+        let mut http = HttpConnector::new_with_resolver(Resolver(GaiResolver::new()));
+        http.enforce_http(false);
+        http.set_connect_timeout(timeouts.connect);
+        http.set_keepalive(keep_alive.tcp);
+        Self {
+            http,
+            proxy_protocol,
+        }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.http.call(uri);
+        let proxy_protocol = self.proxy_protocol;
+        let client_addr = CLIENT_ADDR.try_with(|addr| *addr).ok().flatten();
+
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            if let Some(version) = proxy_protocol {
+                let peer_addr = stream.peer_addr()?;
+                write_header(&mut stream, version, client_addr, peer_addr).await?;
+            }
+            Ok(stream)
+        })
+    }
+}
+
+/// Resolves with `getaddrinfo` like the default resolver, failing with [`DnsError`] to
+/// tell resolution failures apart from connect failures.
+#[derive(Clone)]
+pub(crate) struct Resolver(GaiResolver);
+
+#[derive(Debug, Error)]
+#[error("failed to resolve {host}")]
+pub(crate) struct DnsError {
+    host: String,
+    #[source]
+    source: io::Error,
+}
+
+impl Service<Name> for Resolver {
+    type Response = GaiAddrs;
+    type Error = DnsError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx).map_err(|source| DnsError {
+            host: String::new(),
+            source,
+        })
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.to_string();
+        let resolving = self.0.call(name);
+        Box::pin(async move { resolving.await.map_err(|source| DnsError { host, source }) })
+    }
+}
@@ -1,19 +1,235 @@
+use crate::{connector::DnsError, ListenAddr};
+use http::{header, HeaderValue, StatusCode, Uri};
+use hyper::{Body, Response};
 use rcgen::RcgenError;
-use std::io;
+use std::{error::Error as StdError, fmt, io};
 use thiserror::Error;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("invalid CA")]
     Tls(#[from] RcgenError),
     #[error("network error")]
     HyperError(#[from] hyper::Error),
+    #[cfg(feature = "request-native-tls")]
     #[error("TlsConnector error")]
     TlsConnectorError(#[from] hyper_tls::native_tls::Error),
     #[error("IO error")]
     IO(#[from] io::Error),
+    #[error("invalid certificate")]
+    Rustls(#[from] rustls::Error),
+    #[error("failed to bind {0}")]
+    Bind(ListenAddr, #[source] io::Error),
+    #[error("reverse proxy {0} can't require proxy authentication")]
+    ReverseAuth(ListenAddr),
+    #[error("invalid reverse proxy backend: {0}")]
+    InvalidBackend(http::Uri),
+    #[error("unable to read SNI from client hello")]
+    Sni(#[source] io::Error),
+    #[error("{0} timed out")]
+    Timeout(&'static str),
+    #[error("missing authority in {0}")]
+    MissingAuthority(Uri),
+    #[error("connection upgrade failed")]
+    Upgrade(#[source] hyper::Error),
+    #[error("{kind}: {host}")]
+    Upstream {
+        host: String,
+        kind: UpstreamErrorKind,
+        #[source]
+        source: Option<hyper::Error>,
+    },
+    #[error("PROXY protocol headers can't be sent through an upstream proxy")]
+    ProxyProtocolWithUpstreamProxy,
     #[error("unable to decode response body")]
     Decode,
     #[error("unknown error")]
     Unknown,
 }
+
+/// Why a request to the upstream server failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamErrorKind {
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    Request,
+}
+
+impl fmt::Display for UpstreamErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UpstreamErrorKind::Dns => "dns resolution failed",
+            UpstreamErrorKind::Connect => "connect failed",
+            UpstreamErrorKind::Tls => "tls handshake failed",
+            UpstreamErrorKind::Timeout => "upstream timed out",
+            UpstreamErrorKind::Request => "upstream request failed",
+        })
+    }
+}
+
+impl Error {
+    /// Classify a failed upstream request to `host`.
+    pub(crate) fn upstream(host: String, err: hyper::Error) -> Self {
+        let mut kind = if err.is_connect() {
+            UpstreamErrorKind::Connect
+        } else if err.is_timeout() {
+            UpstreamErrorKind::Timeout
+        } else {
+            UpstreamErrorKind::Request
+        };
+
+        let mut source = err.source();
+        while let Some(cause) = source {
+            if is_tls_error(cause) {
+                kind = UpstreamErrorKind::Tls;
+                break;
+            }
+            if cause.is::<DnsError>() {
+                kind = UpstreamErrorKind::Dns;
+                break;
+            }
+            if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+                if io_err.kind() == io::ErrorKind::TimedOut {
+                    kind = UpstreamErrorKind::Timeout;
+                }
+            }
+            source = cause.source();
+        }
+
+        Error::Upstream {
+            host,
+            kind,
+            source: Some(err),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Timeout(_)
+            | Error::Upstream {
+                kind: UpstreamErrorKind::Timeout,
+                ..
+            } => StatusCode::GATEWAY_TIMEOUT,
+            Error::MissingAuthority(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// An error page describing this error, JSON if `json` is set, otherwise HTML.
+    pub fn to_response(&self, json: bool) -> Response<Body> {
+        let status = self.status_code();
+        let reason = status.canonical_reason().unwrap_or_default();
+        let message = self.to_string();
+
+        let (content_type, body) = if json {
+            (
+                "application/json",
+                format!(
+                    r#"{{"status":{},"error":"{}","message":"{}"}}"#,
+                    status.as_u16(),
+                    reason,
+                    escape_json(&message)
+                ),
+            )
+        } else {
+            (
+                "text/html; charset=utf-8",
+                format!(
+                    "<!DOCTYPE html>\n<html><head><title>{code} {reason}</title></head>\n<body><h1>{code} {reason}</h1><p>{}</p><hr><address>Good-MITM</address></body></html>\n",
+                    escape_html(&message),
+                    code = status.as_u16(),
+                ),
+            )
+        };
+
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, HeaderValue::from_static(content_type))
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+/// Whether the client prefers a JSON error page according to `Accept`.
+pub(crate) fn accepts_json(accept: Option<&HeaderValue>) -> bool {
+    accept
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("json") && !accept.contains("text/html"))
+}
+
+fn is_tls_error(err: &(dyn StdError + 'static)) -> bool {
+    #[cfg(feature = "request-native-tls")]
+    if err.is::<hyper_tls::native_tls::Error>() {
+        return true;
+    }
+    // rustls errors are wrapped in io errors, which hide them from `source`
+    err.is::<rustls::Error>()
+        || err
+            .downcast_ref::<io::Error>()
+            .and_then(|err| err.get_ref())
+            .is_some_and(|err| is_tls_error(err))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connector::UpstreamConnector,
+        timeout::{KeepAlive, Timeouts},
+    };
+    use hyper::Client;
+
+    async fn upstream_error(uri: &str) -> Error {
+        let connector = UpstreamConnector::new(None, &Timeouts::default(), &KeepAlive::default());
+        let client = Client::builder().build::<_, Body>(connector);
+        let uri: Uri = uri.parse().unwrap();
+        let err = client.get(uri.clone()).await.unwrap_err();
+        Error::upstream(uri.host().unwrap().to_owned(), err)
+    }
+
+    fn kind(err: &Error) -> Option<UpstreamErrorKind> {
+        match err {
+            Error::Upstream { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn classifies_dns_errors() {
+        // `.invalid` never resolves
+        let err = upstream_error("http://good-mitm.invalid/").await;
+        assert_eq!(kind(&err), Some(UpstreamErrorKind::Dns));
+    }
+
+    #[tokio::test]
+    async fn classifies_connect_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let err = upstream_error(&format!("http://{addr}/")).await;
+        assert_eq!(kind(&err), Some(UpstreamErrorKind::Connect));
+    }
+}
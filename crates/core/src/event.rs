@@ -0,0 +1,149 @@
+use pin_project::pin_project;
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Connection lifecycle events, see [`EventListener`].
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A client connection was accepted.
+    Accept { client_addr: Option<SocketAddr> },
+    /// A client connection was refused by access control, also when an allow list is
+    /// set and the client has no address, e.g. on a Unix socket.
+    Refuse { client_addr: Option<SocketAddr> },
+    /// A client sent `CONNECT` to `authority`.
+    Connect {
+        client_addr: Option<SocketAddr>,
+        authority: String,
+    },
+    /// The SNI host name was read from a client hello, with its JA3 and JA4 fingerprints.
+    Sni {
+        client_addr: Option<SocketAddr>,
+        host: String,
+        ja3: String,
+        ja4: String,
+    },
+    /// Whether a connection to `host` is intercepted or tunneled, once per connection.
+    MitmDecision {
+        client_addr: Option<SocketAddr>,
+        host: String,
+        intercept: bool,
+    },
+    /// The TLS handshake with a client completed.
+    TlsHandshake {
+        client_addr: Option<SocketAddr>,
+        host: String,
+        duration: Duration,
+    },
+    /// The TLS handshake with a client failed or timed out.
+    TlsHandshakeFailed {
+        client_addr: Option<SocketAddr>,
+        host: String,
+        duration: Duration,
+        error: String,
+    },
+    /// A tunnel was closed, with the bytes copied until then also if it closed with an
+    /// error.
+    TunnelClose {
+        client_addr: Option<SocketAddr>,
+        host: String,
+        sent: u64,
+        received: u64,
+        duration: Duration,
+        error: Option<String>,
+    },
+}
+
+/// Receives connection lifecycle events, called inline so it should return quickly.
+///
+/// Closures taking `&Event` are listeners too.
+pub trait EventListener: Send + Sync + 'static {
+    fn on_event(&self, event: &Event);
+}
+
+impl<F> EventListener for F
+where
+    F: Fn(&Event) + Send + Sync + 'static,
+{
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Events(Option<Arc<dyn EventListener>>);
+
+impl Events {
+    pub fn new(listener: Option<Arc<dyn EventListener>>) -> Self {
+        Self(listener)
+    }
+
+    /// Builds the event only if someone is listening.
+    pub fn emit(&self, event: impl FnOnce() -> Event) {
+        if let Some(ref listener) = self.0 {
+            listener.on_event(&event());
+        }
+    }
+}
+
+/// Counts the bytes read from and written to a stream.
+#[pin_project]
+pub(crate) struct Counted<IO> {
+    #[pin]
+    inner: IO,
+    pub read: u64,
+    pub written: u64,
+}
+
+impl<IO> Counted<IO> {
+    pub fn new(inner: IO) -> Self {
+        Self {
+            inner,
+            read: 0,
+            written: 0,
+        }
+    }
+}
+
+impl<IO: AsyncRead> AsyncRead for Counted<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let rslt = this.inner.poll_read(cx, buf);
+        *this.read += (buf.filled().len() - filled) as u64;
+        rslt
+    }
+}
+
+impl<IO: AsyncWrite> AsyncWrite for Counted<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let rslt = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = rslt {
+            *this.written += written as u64;
+        }
+        rslt
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
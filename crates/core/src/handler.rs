@@ -1,12 +1,20 @@
 use async_trait::async_trait;
 use hyper::{Body, Request, Response};
+use ipnet::IpNet;
 use std::{
+    fmt,
     marker::PhantomData,
+    net::SocketAddr,
+    ops::Deref,
     sync::{Arc, RwLock},
 };
 use wildmatch::WildMatch;
 
-use crate::mitm::{HttpContext, RequestOrResponse};
+use crate::{
+    error::Error,
+    mitm::{HttpContext, RequestOrResponse},
+    resolver::{parse_ip, HostResolver},
+};
 
 pub trait CustomContextData: Clone + Default + Send + Sync + 'static {}
 
@@ -27,42 +35,208 @@ pub trait HttpHandler<D: CustomContextData>: Clone + Send + Sync + 'static {
     ) -> Response<Body> {
         res
     }
+
+    /// Called when the upstream request fails, the returned response is sent to the client.
+    async fn handle_error(&self, ctx: &mut HttpContext<D>, err: &Error) -> Response<Body> {
+        err.to_response(ctx.accept_json)
+    }
+}
+
+/// Decides whether connections to a host are intercepted or tunneled.
+#[async_trait]
+pub trait MitmPolicy<D: CustomContextData>: Send + Sync + 'static {
+    async fn should_intercept(
+        &self,
+        ctx: &HttpContext<D>,
+        host: &str,
+        client_addr: Option<SocketAddr>,
+    ) -> bool;
+}
+
+/// Wildcard patterns of hosts to intercept, or CIDRs matching IP hosts and hosts
+/// resolving into them.
+///
+/// Clones share the list, so it can be updated while the proxy is running.
+#[derive(Clone, Default)]
+pub struct MitmList {
+    patterns: Arc<RwLock<Vec<Pattern>>>,
+    resolver: HostResolver,
+}
+
+impl MitmList {
+    pub fn new(filters: Vec<String>) -> Self {
+        let patterns = filters.iter().map(|f| Pattern::new(f)).collect();
+        Self {
+            patterns: Arc::new(RwLock::new(patterns)),
+            resolver: HostResolver::default(),
+        }
+    }
+
+    /// Resolve hosts for CIDRs with `resolver`, the proxy uses its own unless set.
+    pub fn with_resolver(mut self, resolver: HostResolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Matches the host itself, without resolving it.
+    pub fn matches(&self, host: &str) -> bool {
+        let ip = parse_ip(host);
+        let list = self.patterns.read().unwrap();
+        list.iter().any(|m| match m {
+            Pattern::Host(m) => m.matches(host),
+            Pattern::Cidr(net) => ip.is_some_and(|ip| net.contains(&ip)),
+        })
+    }
+
+    /// Like [`matches`](Self::matches), also resolving hostnames if there are CIDRs.
+    ///
+    /// A host failing to resolve in time matches no CIDR.
+    pub async fn matches_resolved(&self, host: &str) -> bool {
+        if self.matches(host) {
+            return true;
+        }
+        let nets: Vec<IpNet> = self
+            .patterns
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|m| match m {
+                Pattern::Cidr(net) => Some(*net),
+                Pattern::Host(_) => None,
+            })
+            .collect();
+        if nets.is_empty() || parse_ip(host).is_some() {
+            return false;
+        }
+        self.resolver
+            .resolve(host)
+            .await
+            .iter()
+            .any(|ip| nets.iter().any(|net| net.contains(ip)))
+    }
+
+    /// Replace all patterns.
+    pub fn set(&self, filters: Vec<String>) {
+        *self.patterns.write().unwrap() = filters.iter().map(|f| Pattern::new(f)).collect();
+    }
+
+    pub fn add(&self, filter: &str) {
+        self.patterns.write().unwrap().push(Pattern::new(filter));
+    }
+
+    /// Remove a pattern, returns whether it was in the list.
+    pub fn remove(&self, filter: &str) -> bool {
+        let mut list = self.patterns.write().unwrap();
+        let len = list.len();
+        // `WildMatch == &str` matches a host, compare the patterns instead
+        list.retain(|m| m.to_string().as_str() != filter);
+        list.len() != len
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let list = self.patterns.read().unwrap();
+        list.iter().map(ToString::to_string).collect()
+    }
+}
+
+impl From<Vec<String>> for MitmList {
+    fn from(filters: Vec<String>) -> Self {
+        Self::new(filters)
+    }
+}
+
+impl fmt::Debug for MitmList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.list()).finish()
+    }
 }
 
+/// The [`MitmPolicy`] of a [`MitmList`], which it derefs to.
 #[derive(Clone, Default)]
 pub struct MitmFilter<D: CustomContextData> {
-    filters: Arc<RwLock<Vec<WildMatch>>>,
+    list: MitmList,
 
     _custom_contex_data: PhantomData<D>,
 }
 
 impl<D: CustomContextData> MitmFilter<D> {
     pub fn new(filters: Vec<String>) -> Self {
-        let filters = filters.iter().map(|f| WildMatch::new(f)).collect();
+        MitmList::new(filters).into()
+    }
+
+    #[deprecated(note = "use `matches` or `MitmPolicy::should_intercept`")]
+    pub async fn filter_req(&self, _ctx: &HttpContext<D>, req: &Request<Body>) -> bool {
+        self.matches(req.uri().host().unwrap_or_default())
+    }
+
+    #[deprecated(note = "use `matches` or `MitmPolicy::should_intercept`")]
+    pub async fn filter(&self, host: &str) -> bool {
+        self.matches(host)
+    }
+}
+
+impl<D: CustomContextData> From<MitmList> for MitmFilter<D> {
+    fn from(list: MitmList) -> Self {
         Self {
-            filters: Arc::new(RwLock::new(filters)),
-            ..Default::default()
+            list,
+            _custom_contex_data: PhantomData,
         }
     }
+}
 
-    pub async fn filter_req(&self, _ctx: &HttpContext<D>, req: &Request<Body>) -> bool {
-        let host = req.uri().host().unwrap_or_default();
-        let list = self.filters.read().unwrap();
-        for m in list.iter() {
-            if m.matches(host) {
-                return true;
-            }
+impl<D: CustomContextData> Deref for MitmFilter<D> {
+    type Target = MitmList;
+
+    fn deref(&self) -> &MitmList {
+        &self.list
+    }
+}
+
+#[async_trait]
+impl<D: CustomContextData> MitmPolicy<D> for MitmFilter<D> {
+    async fn should_intercept(
+        &self,
+        _ctx: &HttpContext<D>,
+        host: &str,
+        _client_addr: Option<SocketAddr>,
+    ) -> bool {
+        self.matches_resolved(host).await
+    }
+}
+
+enum Pattern {
+    Host(WildMatch),
+    Cidr(IpNet),
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Self {
+        match pattern.parse() {
+            Ok(net) => Self::Cidr(net),
+            Err(_) => Self::Host(WildMatch::new(pattern)),
         }
-        false
     }
+}
 
-    pub async fn filter(&self, host: &str) -> bool {
-        let list = self.filters.read().unwrap();
-        for m in list.iter() {
-            if m.matches(host) {
-                return true;
-            }
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Host(m) => m.fmt(f),
+            Self::Cidr(net) => net.fmt(f),
         }
-        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolves_for_cidrs() {
+        let list = MitmList::new(vec!["127.0.0.0/8".to_owned(), "*.example.com".to_owned()]);
+        assert!(list.matches_resolved("localhost").await);
+        assert!(list.matches_resolved("www.example.com").await);
+        assert!(list.matches_resolved("127.0.0.1").await);
+        assert!(!list.matches_resolved("[::1]").await);
     }
 }
@@ -1,8 +1,29 @@
-use crate::error::Error;
-use hyper::{client::HttpConnector, Client};
+#[cfg(unix)]
+use crate::unix::{UnixConnector, UnixRoutes};
+use crate::{
+    client_hello::ClientHello,
+    connector::{UpstreamConnector, CLIENT_ADDR},
+    error::Error,
+    proxy_protocol::ProxyProtocolVersion,
+    timeout::{KeepAlive, Timeouts},
+    tls_mirror::{MirrorKey, TlsMirror},
+};
+use http::uri::Scheme;
+use hyper::{
+    client::{connect::Connect, Builder, ResponseFuture},
+    Body, Client, Request, Response,
+};
 use hyper_proxy::{Proxy as UpstreamProxy, ProxyConnector};
+use log::error;
 use rustls::client::{ServerCertVerified, ServerCertVerifier};
-use std::time::SystemTime;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use wildmatch::WildMatch;
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "request-native-tls")] {
@@ -10,17 +31,138 @@ cfg_if::cfg_if! {
     } else {
         use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
         use rustls::ClientConfig;
-        use std::sync::Arc;
     }
 }
 
+/// Mirrored clients kept, one per distinct ClientHello.
+const MIRROR_CLIENTS_CAPACITY: usize = 64;
+
 #[derive(Clone)]
-pub enum HttpClient {
-    Proxy(Client<ProxyConnector<HttpsConnector<HttpConnector>>>),
-    Https(Client<HttpsConnector<HttpConnector>>),
+pub struct HttpClient {
+    client: UpstreamClient<HttpsConnector<UpstreamConnector>>,
+    mirror: Option<Arc<MirrorClients>>,
+    #[cfg(unix)]
+    unix_client: Option<(UnixRoutes, Client<UnixConnector>)>,
+}
+
+#[derive(Clone)]
+enum UpstreamClient<C> {
+    Proxy(Client<ProxyConnector<C>>),
+    Https(Client<C>),
+}
+
+impl<C> UpstreamClient<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    ProxyConnector<C>: Connect + Clone + Send + Sync + 'static,
+{
+    fn new(
+        builder: &Builder,
+        https: C,
+        upstream_proxy: Option<UpstreamProxy>,
+    ) -> Result<Self, Error> {
+        Ok(match upstream_proxy {
+            Some(proxy) => {
+                let connector = ProxyConnector::from_proxy(https, proxy)?;
+                UpstreamClient::Proxy(builder.build(connector))
+            }
+            None => UpstreamClient::Https(builder.build(https)),
+        })
+    }
+
+    fn request(&self, req: Request<Body>) -> ResponseFuture {
+        match self {
+            UpstreamClient::Proxy(client) => client.request(req),
+            UpstreamClient::Https(client) => client.request(req),
+        }
+    }
+}
+
+/// Clients offering what the client offered, see [`TlsMirror`].
+struct MirrorClients {
+    mirror: TlsMirror,
+    hosts: Vec<WildMatch>,
+    builder: Builder,
+    connector: UpstreamConnector,
+    upstream_proxy: Option<UpstreamProxy>,
+    clients:
+        Mutex<HashMap<MirrorKey, UpstreamClient<hyper_rustls::HttpsConnector<UpstreamConnector>>>>,
+}
+
+impl MirrorClients {
+    fn matches(&self, host: &str) -> bool {
+        self.hosts.iter().any(|m| m.matches(host))
+    }
+
+    fn client(
+        &self,
+        hello: &ClientHello,
+    ) -> Result<UpstreamClient<hyper_rustls::HttpsConnector<UpstreamConnector>>, Error> {
+        let key = self.mirror.key(hello);
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+
+        let config = key.client_config(Arc::new(TrustAllCertVerifier));
+        let https = hyper_rustls::HttpsConnector::from((self.connector.clone(), config));
+        let client = UpstreamClient::new(&self.builder, https, self.upstream_proxy.clone())?;
+        if clients.len() >= MIRROR_CLIENTS_CAPACITY {
+            clients.clear();
+        }
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+}
+
+impl HttpClient {
+    pub async fn request(
+        &self,
+        req: Request<Body>,
+        client_addr: Option<SocketAddr>,
+        client_hello: Option<&ClientHello>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        #[cfg(unix)]
+        if let Some((ref routes, ref client)) = self.unix_client {
+            if routes.get(req.uri()).is_some() {
+                return client.request(req).await;
+            }
+        }
+
+        let mirror = match (&self.mirror, client_hello) {
+            (Some(mirror), Some(hello))
+                if req.uri().scheme() == Some(&Scheme::HTTPS)
+                    && mirror.matches(req.uri().host().unwrap_or_default()) =>
+            {
+                mirror
+                    .client(hello)
+                    .map_err(|err| error!("Failed to build the mirrored client: {err}"))
+                    .ok()
+            }
+            _ => None,
+        };
+        let res = match mirror {
+            Some(client) => client.request(req),
+            None => self.client.request(req),
+        };
+        CLIENT_ADDR.scope(client_addr, res).await
+    }
 }
 
-pub fn gen_client(upstream_proxy: Option<UpstreamProxy>) -> Result<HttpClient, Error> {
+pub fn gen_client(
+    upstream_proxy: Option<UpstreamProxy>,
+    unix_routes: HashMap<String, PathBuf>,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    timeouts: &Timeouts,
+    keep_alive: &KeepAlive,
+    tls_mirror: Option<TlsMirror>,
+) -> Result<HttpClient, Error> {
+    // the header would reach the proxy instead of the upstream server
+    if upstream_proxy.is_some() && proxy_protocol.is_some() {
+        return Err(Error::ProxyProtocolWithUpstreamProxy);
+    }
+    let http = UpstreamConnector::new(proxy_protocol, timeouts, keep_alive);
+
     cfg_if::cfg_if! {
         if #[cfg(feature = "request-native-tls")] {
             let https = {
@@ -29,15 +171,13 @@ pub fn gen_client(upstream_proxy: Option<UpstreamProxy>) -> Result<HttpClient, E
                     .danger_accept_invalid_hostnames(true)
                     .disable_built_in_roots(true)
                     .build()?;
-                let mut http = HttpConnector::new();
-                http.enforce_http(false);
-                HttpsConnector::from((http, tls.into()))
+                HttpsConnector::from((http.clone(), tls.into()))
             };
         } else {
             let https = {
                 let https_builder = HttpsConnectorBuilder::new()
                     .with_tls_config({
-                        let cert_resolver = Arc::new(TrustAllCertVerifier::default());
+                        let cert_resolver = Arc::new(TrustAllCertVerifier);
                         ClientConfig::builder()
                             .with_safe_defaults()
                             .with_custom_certificate_verifier(cert_resolver)
@@ -48,27 +188,62 @@ pub fn gen_client(upstream_proxy: Option<UpstreamProxy>) -> Result<HttpClient, E
                 #[cfg(feature = "h2")]
                 let https_builder = https_builder.enable_http2();
 
-                https_builder.build()
+                https_builder.wrap_connector(http.clone())
             };
         }
     }
 
-    if let Some(proxy) = upstream_proxy {
-        let connector = ProxyConnector::from_proxy(https, proxy)?;
-        return Ok(HttpClient::Proxy(
-            Client::builder()
-                .http1_title_case_headers(true)
-                .http1_preserve_header_case(true)
-                .build(connector),
-        ));
-    } else {
-        Ok(HttpClient::Https(
-            Client::builder()
+    let mut builder = Client::builder();
+    builder
+        .http1_title_case_headers(true)
+        .http1_preserve_header_case(true)
+        .pool_max_idle_per_host(keep_alive.pool_max_idle_per_host)
+        .pool_idle_timeout(keep_alive.pool_idle_timeout)
+        .http2_keep_alive_interval(keep_alive.http2_interval);
+    if proxy_protocol.is_some() {
+        // a pooled connection would announce the client it was opened for
+        builder.pool_max_idle_per_host(0);
+    }
+
+    let client = UpstreamClient::new(&builder, https, upstream_proxy.clone())?;
+
+    let mirror = tls_mirror.map(|mirror| {
+        Arc::new(MirrorClients {
+            hosts: mirror.matcher(),
+            mirror,
+            builder: builder.clone(),
+            connector: http,
+            upstream_proxy,
+            clients: Default::default(),
+        })
+    });
+
+    // Unix routes are dialed directly, plain HTTP is spoken over the socket.
+    #[cfg(unix)]
+    let unix_client = {
+        let routes = UnixRoutes::new(unix_routes);
+        (!routes.is_empty()).then(|| {
+            let client = Client::builder()
                 .http1_title_case_headers(true)
                 .http1_preserve_header_case(true)
-                .build(https),
-        ))
+                .pool_max_idle_per_host(keep_alive.pool_max_idle_per_host)
+                .pool_idle_timeout(keep_alive.pool_idle_timeout)
+                .build(UnixConnector::new(routes.clone()));
+            (routes, client)
+        })
+    };
+
+    #[cfg(not(unix))]
+    if !unix_routes.is_empty() {
+        log::warn!("unix routes are not supported on this platform");
     }
+
+    Ok(HttpClient {
+        client,
+        mirror,
+        #[cfg(unix)]
+        unix_client,
+    })
 }
 
 #[derive(Default)]
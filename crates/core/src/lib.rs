@@ -1,23 +1,62 @@
 use error::Error;
-use handler::{CustomContextData, HttpHandler, MitmFilter};
+use event::Events;
+use handler::{CustomContextData, HttpHandler, MitmFilter, MitmList, MitmPolicy};
 use http_client::gen_client;
 use hyper_proxy::Proxy as UpstreamProxy;
+use log::*;
 use mitm::MitmProxy;
-use std::{future::Future, marker::PhantomData, net::SocketAddr, sync::Arc};
-use tokio::net::TcpListener;
+use proxy_protocol::{read_header, HEADER_TIMEOUT};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::{
+    collections::HashMap, future::Future, io, marker::PhantomData, net::SocketAddr, path::PathBuf,
+    sync::Arc,
+};
+use timeout::IdleTimeout;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    task::JoinSet,
+};
 use typed_builder::TypedBuilder;
 
+pub use access::AccessControl;
+pub use auth::ProxyAuth;
 pub use ca::CertificateAuthority;
+pub use client_hello::ClientHello;
+pub use event::{Event, EventListener};
 pub use hyper;
+pub use ipnet;
+pub use listener::{ListenAddr, Listener, ListenerMode, ReverseProxy};
+pub use passthrough::Passthrough;
+pub use proxy_protocol::ProxyProtocolVersion;
 pub use rcgen;
+pub use resolver::HostResolver;
+pub use timeout::{KeepAlive, Timeouts};
+pub use tls_mirror::TlsMirror;
 pub use tokio_rustls;
 
+mod access;
+mod auth;
 mod ca;
-mod error;
+mod client_hello;
+mod connector;
+pub mod error;
+mod event;
 pub mod handler;
 mod http_client;
+mod listener;
 pub mod mitm;
+mod passthrough;
+mod proxy_protocol;
+mod resolver;
 mod sni_reader;
+mod timeout;
+mod tls_mirror;
+#[cfg(unix)]
+mod unix;
 
 #[derive(TypedBuilder)]
 pub struct Proxy<F, H, D>
@@ -26,15 +65,59 @@ where
     H: HttpHandler<D>,
     D: CustomContextData,
 {
-    /// The address to listen on.
-    pub listen_addr: SocketAddr,
+    /// The address to listen on, using the proxy-wide settings.
+    #[builder(default, setter(strip_option))]
+    pub listen_addr: Option<SocketAddr>,
+    /// Additional listeners with their own settings.
+    #[builder(default)]
+    pub listeners: Vec<Listener>,
     /// A future that once resolved will cause the proxy server to shut down.
     pub shutdown_signal: F,
     /// The certificate authority to use.
     pub ca: CertificateAuthority,
     pub upstream_proxy: Option<UpstreamProxy>,
+    /// Upstream hosts served by a Unix socket instead of the network, e.g. `docker` to
+    /// `/var/run/docker.sock`. Tunneled `CONNECT`s still use the network.
+    #[builder(default)]
+    pub unix_routes: HashMap<String, PathBuf>,
+    /// Announce clients to upstream servers with a PROXY protocol header, not together
+    /// with `upstream_proxy`.
+    #[builder(default)]
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Require `Proxy-Authorization` from clients when set.
+    #[builder(default)]
+    pub auth: Option<ProxyAuth>,
+    /// Client addresses allowed to use the proxy.
+    #[builder(default)]
+    pub access_control: AccessControl,
+    /// Connect, handshake, idle and other timeouts.
+    #[builder(default)]
+    pub timeouts: Timeouts,
+    /// Keep-alive of client and upstream connections.
+    #[builder(default)]
+    pub keep_alive: KeepAlive,
+    /// Receives connection lifecycle events.
+    #[builder(default, setter(strip_option))]
+    pub event_listener: Option<Arc<dyn EventListener>>,
 
-    pub mitm_filters: Vec<String>,
+    /// Domains to MITM, unless `mitm_policy` is set. Keep a clone of the list to update it
+    /// while the proxy is running.
+    #[builder(default, setter(into))]
+    pub mitm_filters: MitmList,
+    /// Decides which hosts are intercepted instead of `mitm_filters`. Listeners with their
+    /// own `mitm_filters` use those instead of either.
+    #[builder(default, setter(strip_option))]
+    pub mitm_policy: Option<Arc<dyn MitmPolicy<D>>>,
+    /// Resolves hosts for the CIDRs of MITM lists. Share it with the handler to share the
+    /// cache, by default one with the connect timeout.
+    #[builder(default, setter(strip_option))]
+    pub resolver: Option<HostResolver>,
+    /// Tunnel hosts whose clients keep rejecting the forged certificate.
+    #[builder(default)]
+    pub passthrough: Option<Passthrough>,
+    /// Offer upstream servers what the client offered in its ClientHello.
+    #[builder(default)]
+    pub tls_mirror: Option<TlsMirror>,
     pub handler: H,
 
     #[builder(default)]
@@ -48,40 +131,200 @@ where
     D: CustomContextData,
 {
     pub async fn start_proxy(self) -> Result<(), Error> {
-        let client = gen_client(self.upstream_proxy)?;
+        let client = gen_client(
+            self.upstream_proxy,
+            self.unix_routes,
+            self.send_proxy_protocol,
+            &self.timeouts,
+            &self.keep_alive,
+            self.tls_mirror,
+        )?;
         let ca = Arc::new(self.ca);
         let http_handler = Arc::new(self.handler);
-        let mitm_filter = Arc::new(MitmFilter::new(self.mitm_filters));
-
-        let tcp_listener = TcpListener::bind(self.listen_addr).await?;
-        loop {
-            let client = client.clone();
-            let ca = Arc::clone(&ca);
-            let http_handler = Arc::clone(&http_handler);
-            let mitm_filter = Arc::clone(&mitm_filter);
-
-            if let Ok((tcp_stream, _)) = tcp_listener.accept().await {
-                tokio::spawn(async move {
-                    let mitm_proxy = MitmProxy {
-                        ca: ca.clone(),
-                        client: client.clone(),
-                        http_handler: Arc::clone(&http_handler),
-                        mitm_filter: Arc::clone(&mitm_filter),
-                        custom_contex_data: Default::default(),
-                    };
-
-                    let mut tls_content_type = [0; 1];
-                    if tcp_stream.peek(&mut tls_content_type).await.is_ok() {
-                        if tls_content_type[0] <= 0x40 {
-                            // ASCII < 'A', assuming tls
-                            mitm_proxy.serve_tls(tcp_stream).await;
-                        } else {
-                            // assuming http
-                            _ = mitm_proxy.serve_stream(tcp_stream).await;
-                        }
-                    }
-                });
+        let resolver = self
+            .resolver
+            .unwrap_or_else(|| HostResolver::new(self.timeouts.connect));
+        let mitm_filter = |list: MitmList| -> Arc<dyn MitmPolicy<D>> {
+            Arc::new(MitmFilter::from(list.with_resolver(resolver.clone())))
+        };
+        let mitm_policy = self
+            .mitm_policy
+            .unwrap_or_else(|| mitm_filter(self.mitm_filters));
+        let events = Events::new(self.event_listener);
+
+        let mut listeners = self.listeners;
+        if let Some(addr) = self.listen_addr {
+            listeners.insert(
+                0,
+                Listener {
+                    addr: addr.into(),
+                    mode: ListenerMode::Proxy,
+                    auth: self.auth,
+                    access_control: self.access_control,
+                    mitm_filters: None,
+                    proxy_protocol: false,
+                },
+            );
+        }
+
+        // bind every listener before serving any, so a failing one stops the start
+        let mut bound = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            if listener.auth.is_some() && matches!(listener.mode, ListenerMode::Reverse(_)) {
+                return Err(Error::ReverseAuth(listener.addr));
+            }
+            match bind(&listener).await {
+                Ok(socket) => bound.push((listener, socket)),
+                Err(err) => return Err(Error::Bind(listener.addr, err)),
+            }
+        }
+
+        let mut tasks = JoinSet::new();
+        for (listener, bound) in bound {
+            info!("{} listen on: {}", listener.mode, listener.addr);
+
+            let mitm_proxy = MitmProxy {
+                ca: Arc::clone(&ca),
+                client: client.clone(),
+                http_handler: Arc::clone(&http_handler),
+                mitm_policy: match listener.mitm_filters {
+                    Some(list) => mitm_filter(list),
+                    None => Arc::clone(&mitm_policy),
+                },
+                mode: listener.mode,
+                send_proxy_protocol: self.send_proxy_protocol,
+                timeouts: self.timeouts,
+                keep_alive: self.keep_alive,
+                events: events.clone(),
+                passthrough: self.passthrough.clone(),
+                auth: listener.auth.map(Arc::new),
+                username: None,
+                client_addr: None,
+                client_hello: None,
+                custom_contex_data: Default::default(),
+            };
+            tasks.spawn(serve_listener(
+                bound,
+                listener.proxy_protocol,
+                Arc::new(listener.access_control),
+                mitm_proxy,
+            ));
+        }
+
+        tokio::select! {
+            _ = self.shutdown_signal => {}
+            Some(_) = tasks.join_next() => {}
+        }
+        tasks.shutdown().await;
+        Ok(())
+    }
+}
+
+enum BoundListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+async fn bind(listener: &Listener) -> io::Result<BoundListener> {
+    Ok(match listener.addr {
+        ListenAddr::Tcp(addr) => BoundListener::Tcp(TcpListener::bind(addr).await?),
+        #[cfg(unix)]
+        ListenAddr::Unix(ref path) => {
+            if !listener.proxy_protocol && !listener.access_control.allow.is_empty() {
+                warn!(
+                    "{} has no client addresses, the allow list refuses all connections",
+                    listener.addr
+                );
+            }
+            // remove the socket left by a previous run
+            if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            BoundListener::Unix(UnixListener::bind(path)?)
+        }
+    })
+}
+
+async fn serve_listener<H, D>(
+    listener: BoundListener,
+    proxy_protocol: bool,
+    access_control: Arc<AccessControl>,
+    mitm_proxy: MitmProxy<H, D>,
+) where
+    H: HttpHandler<D>,
+    D: CustomContextData,
+{
+    loop {
+        match listener {
+            BoundListener::Tcp(ref tcp_listener) => {
+                if let Ok((tcp_stream, peer_addr)) = tcp_listener.accept().await {
+                    tokio::spawn(accept_conn(
+                        tcp_stream,
+                        Some(peer_addr),
+                        proxy_protocol,
+                        access_control.clone(),
+                        mitm_proxy.clone(),
+                    ));
+                }
+            }
+            #[cfg(unix)]
+            BoundListener::Unix(ref unix_listener) => {
+                if let Ok((unix_stream, _)) = unix_listener.accept().await {
+                    tokio::spawn(accept_conn(
+                        unix_stream,
+                        None,
+                        proxy_protocol,
+                        access_control.clone(),
+                        mitm_proxy.clone(),
+                    ));
+                }
             }
         }
     }
 }
+
+async fn accept_conn<IO, H, D>(
+    stream: IO,
+    peer_addr: Option<SocketAddr>,
+    proxy_protocol: bool,
+    access_control: Arc<AccessControl>,
+    mut mitm_proxy: MitmProxy<H, D>,
+) where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    H: HttpHandler<D>,
+    D: CustomContextData,
+{
+    let mut stream = IdleTimeout::new(stream, mitm_proxy.timeouts.idle);
+
+    // behind a load balancer the real client is announced before anything else
+    let client_addr = if proxy_protocol {
+        let timeout = mitm_proxy.timeouts.header_read.unwrap_or(HEADER_TIMEOUT);
+        match tokio::time::timeout(timeout, read_header(&mut stream)).await {
+            Ok(Ok(client_addr)) => client_addr.or(peer_addr),
+            Ok(Err(err)) => {
+                debug!("invalid PROXY protocol header from {peer_addr:?}: {err}");
+                return;
+            }
+            Err(_) => {
+                debug!(
+                    "{peer_addr:?}: {}",
+                    Error::Timeout("reading PROXY protocol header")
+                );
+                return;
+            }
+        }
+    } else {
+        peer_addr
+    };
+
+    if !access_control.is_allowed_addr(client_addr.map(|addr| addr.ip())) {
+        debug!("refuse connection from {client_addr:?}");
+        mitm_proxy.events.emit(|| Event::Refuse { client_addr });
+        return;
+    }
+    mitm_proxy.events.emit(|| Event::Accept { client_addr });
+
+    mitm_proxy.client_addr = client_addr;
+    mitm_proxy.serve_conn(stream).await
+}
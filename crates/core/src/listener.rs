@@ -0,0 +1,171 @@
+use crate::{access::AccessControl, auth::ProxyAuth, error::Error, handler::MitmList};
+use http::{uri::Scheme, Uri};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{
+    fmt,
+    net::{AddrParseError, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio_rustls::rustls::ServerConfig;
+use typed_builder::TypedBuilder;
+
+/// A TCP address, or a Unix socket path written as `unix:/path/to.sock`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<PathBuf> for ListenAddr {
+    fn from(path: PathBuf) -> Self {
+        Self::Unix(path)
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()));
+        }
+        s.parse().map(Self::Tcp)
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// How connections accepted by a listener are handled.
+#[derive(Clone, Debug, Default)]
+pub enum ListenerMode {
+    /// HTTP proxy with `CONNECT` support, also accepting redirected TLS and HTTP traffic.
+    #[default]
+    Proxy,
+    /// Only redirected TLS and HTTP traffic, `CONNECT` requests are refused.
+    Transparent,
+    /// Forward every request to a fixed backend, clients connect as if it were the origin.
+    Reverse(ReverseProxy),
+}
+
+impl fmt::Display for ListenerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Proxy => write!(f, "Proxy"),
+            Self::Transparent => write!(f, "Transparent"),
+            Self::Reverse(reverse) => write!(f, "Reverse proxy to {}", reverse.backend),
+        }
+    }
+}
+
+/// The backend origin of a reverse proxy listener.
+#[derive(Clone)]
+pub struct ReverseProxy {
+    pub(crate) backend: Uri,
+    pub(crate) server_config: Option<Arc<ServerConfig>>,
+}
+
+impl ReverseProxy {
+    /// The backend must be an origin like `http://127.0.0.1:8080`, without a path.
+    pub fn new(backend: Uri) -> Result<Self, Error> {
+        if backend.authority().is_none()
+            || !matches!(backend.scheme_str(), Some("http") | Some("https"))
+            || backend.path_and_query().is_some_and(|p| p.as_str() != "/")
+        {
+            return Err(Error::InvalidBackend(backend));
+        }
+
+        Ok(Self {
+            backend,
+            server_config: None,
+        })
+    }
+
+    /// Terminate HTTPS with this certificate instead of one signed by the CA.
+    pub fn with_cert(
+        mut self,
+        cert_chain: Vec<rustls::Certificate>,
+        key: rustls::PrivateKey,
+    ) -> Result<Self, Error> {
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)?;
+        self.server_config = Some(Arc::new(server_config));
+        Ok(self)
+    }
+
+    pub(crate) fn scheme(&self) -> Scheme {
+        self.backend.scheme().cloned().unwrap_or(Scheme::HTTP)
+    }
+}
+
+impl fmt::Debug for ReverseProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReverseProxy")
+            .field("backend", &self.backend)
+            .field("custom_cert", &self.server_config.is_some())
+            .finish()
+    }
+}
+
+/// Settings for one address the proxy listens on.
+///
+/// All listeners of a [`Proxy`](crate::Proxy) share the CA, the certificate cache, the
+/// upstream client and the handler.
+#[derive(Clone, Debug, TypedBuilder)]
+pub struct Listener {
+    /// The address to listen on.
+    #[builder(setter(into))]
+    pub addr: ListenAddr,
+    #[builder(default)]
+    pub mode: ListenerMode,
+    /// Require `Proxy-Authorization` from clients when set. Reverse listeners aren't used
+    /// as a proxy, the proxy refuses to start when one has it.
+    #[builder(default)]
+    pub auth: Option<ProxyAuth>,
+    /// Client addresses allowed to use this listener.
+    #[builder(default)]
+    pub access_control: AccessControl,
+    /// Domains to MITM on this listener instead of the proxy-wide `mitm_policy` or
+    /// `mitm_filters`. Keep a clone to update it while the proxy is running.
+    #[builder(default)]
+    pub mitm_filters: Option<MitmList>,
+    /// Expect a PROXY protocol header on every connection, e.g. behind HAProxy.
+    #[builder(default)]
+    pub proxy_protocol: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_are_origins() {
+        let backend = |uri: &str| ReverseProxy::new(uri.parse().unwrap()).is_ok();
+        assert!(backend("http://127.0.0.1:8080"));
+        assert!(backend("https://example.com/"));
+        assert!(!backend("http://example.com/app/"));
+        assert!(!backend("http://example.com/?a=1"));
+        assert!(!backend("ftp://example.com"));
+        assert!(!backend("/app"));
+    }
+}
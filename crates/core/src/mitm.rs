@@ -1,20 +1,35 @@
 use crate::{
+    auth::ProxyAuth,
     ca::CertificateAuthority,
-    handler::{CustomContextData, HttpHandler, MitmFilter},
+    client_hello::ClientHello,
+    error::{accepts_json, Error, UpstreamErrorKind},
+    event::{Counted, Event, Events},
+    handler::{CustomContextData, HttpHandler, MitmPolicy},
     http_client::HttpClient,
+    listener::{ListenerMode, ReverseProxy},
+    passthrough::Passthrough,
+    proxy_protocol::{write_header, ProxyProtocolVersion},
     sni_reader::{
-        read_sni_host_name_from_client_hello, HandshakeRecordReader, PrefixedReaderWriter,
-        RecordingBufReader,
+        read_client_hello, HandshakeRecordReader, PrefixedReaderWriter, RecordingBufReader,
     },
+    timeout::{with_timeout, KeepAlive, Timeouts},
+};
+use http::{
+    header,
+    header::HeaderName,
+    uri::{Authority, Scheme},
+    HeaderValue, Uri,
 };
-use http::{header, uri::Scheme, HeaderValue, Uri};
 use hyper::{
     body::HttpBody, server::conn::Http, service::service_fn, Body, Method, Request, Response,
 };
 use log::*;
-use std::{marker::PhantomData, sync::Arc, time::Duration};
+use std::{
+    convert::Infallible, error::Error as _, io, marker::PhantomData, net::SocketAddr, sync::Arc,
+    time::Instant,
+};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
     net::TcpStream,
     pin,
 };
@@ -31,6 +46,14 @@ pub enum RequestOrResponse {
 #[derive(Default, Debug)]
 pub struct HttpContext<D: Default + Send + Sync> {
     pub uri: Option<Uri>,
+    /// The user authenticated by `Proxy-Authorization`, if the listener requires it.
+    pub username: Option<String>,
+    /// The address of the connected client.
+    pub client_addr: Option<SocketAddr>,
+    /// Whether the client accepts JSON, error pages are rendered as JSON then.
+    pub accept_json: bool,
+    /// The TLS ClientHello of the client, for intercepted HTTPS.
+    pub client_hello: Option<Arc<ClientHello>>,
 
     pub should_modify_response: bool,
     pub custom_data: D,
@@ -46,7 +69,18 @@ where
     pub client: HttpClient,
 
     pub http_handler: Arc<H>,
-    pub mitm_filter: Arc<MitmFilter<D>>,
+    pub mitm_policy: Arc<dyn MitmPolicy<D>>,
+    pub passthrough: Option<Passthrough>,
+
+    pub mode: ListenerMode,
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+    pub timeouts: Timeouts,
+    pub keep_alive: KeepAlive,
+    pub events: Events,
+    pub auth: Option<Arc<ProxyAuth>>,
+    pub username: Option<String>,
+    pub client_addr: Option<SocketAddr>,
+    pub client_hello: Option<Arc<ClientHello>>,
 
     pub custom_contex_data: PhantomData<D>,
 }
@@ -57,40 +91,50 @@ where
     D: CustomContextData,
 {
     pub(crate) async fn proxy_req(
-        self,
-        req: Request<Body>,
-    ) -> Result<Response<Body>, hyper::Error> {
-        let res = if req.method() == Method::CONNECT {
-            self.process_connect(req).await
+        mut self,
+        mut req: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        if let Some(auth) = &self.auth {
+            match auth.authenticate(req.headers().get(header::PROXY_AUTHORIZATION)) {
+                Some(username) => self.username = Some(username),
+                None => {
+                    debug!("proxy authentication required: {}", req.uri());
+                    return Ok(proxy_auth_required_res());
+                }
+            }
+        }
+        req.headers_mut().remove(header::PROXY_AUTHORIZATION);
+
+        let mut res = if req.method() == Method::CONNECT {
+            if matches!(self.mode, ListenerMode::Transparent) {
+                return Ok(Response::builder()
+                    .status(http::StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::empty())
+                    .unwrap());
+            }
+            self.process_connect(req).await?
         } else {
-            self.process_request(req, Scheme::HTTP).await
+            self.process_request(req, Scheme::HTTP).await?
         };
 
-        match res {
-            Ok(mut res) => {
-                allow_all_cros(&mut res);
-                Ok(res)
-            }
-            Err(err) => {
-                error!("proxy request failed: {err:?}");
-                Err(err)
-            }
-        }
+        allow_all_cros(&mut res);
+        Ok(res)
     }
 
     async fn process_request(
         self,
         mut req: Request<Body>,
         scheme: Scheme,
-    ) -> Result<Response<Body>, hyper::Error> {
-        if req.uri().path().starts_with("/mitm/cert") {
+    ) -> Result<Response<Body>, Infallible> {
+        if !matches!(self.mode, ListenerMode::Reverse(_))
+            && req.uri().path().starts_with("/mitm/cert")
+        {
             return Ok(self.get_cert_res());
         }
 
         let mut ctx = HttpContext {
-            uri: None,
-            should_modify_response: false,
-            ..Default::default()
+            accept_json: accepts_json(req.headers().get(header::ACCEPT)),
+            ..self.new_ctx()
         };
 
         // if req.uri().authority().is_none() {
@@ -124,9 +168,29 @@ where
             header_mut.remove(http::header::CONTENT_LENGTH);
         }
 
-        let res = match self.client {
-            HttpClient::Proxy(client) => client.request(req).await?,
-            HttpClient::Https(client) => client.request(req).await?,
+        let host = req.uri().host().unwrap_or_default().to_owned();
+        let res = match with_timeout(
+            self.timeouts.upstream_response,
+            self.client
+                .request(req, self.client_addr, self.client_hello.as_deref()),
+        )
+        .await
+        {
+            Ok(Ok(res)) => res,
+            Ok(Err(err)) => {
+                let err = Error::upstream(host, err);
+                log_error(&err);
+                return Ok(self.http_handler.handle_error(&mut ctx, &err).await);
+            }
+            Err(_) => {
+                let err = Error::Upstream {
+                    host,
+                    kind: UpstreamErrorKind::Timeout,
+                    source: None,
+                };
+                log_error(&err);
+                return Ok(self.http_handler.handle_error(&mut ctx, &err).await);
+            }
         };
 
         let mut res = self.http_handler.handle_response(&mut ctx, res).await;
@@ -147,70 +211,230 @@ where
         Ok(res)
     }
 
-    async fn process_connect(self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-        let ctx = HttpContext {
-            uri: None,
-            should_modify_response: false,
-            ..Default::default()
+    async fn process_connect(self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let Some(authority) = req.uri().authority().cloned() else {
+            let err = Error::MissingAuthority(req.uri().clone());
+            log_error(&err);
+            return Ok(err.to_response(accepts_json(req.headers().get(header::ACCEPT))));
         };
 
-        if self.mitm_filter.filter_req(&ctx, &req).await {
-            tokio::task::spawn(async move {
-                let authority = req
-                    .uri()
-                    .authority()
-                    .expect("URI does not contain authority")
-                    .clone();
-
-                match hyper::upgrade::on(req).await {
-                    Ok(upgraded) => {
-                        self.serve_tls(upgraded).await;
-                    }
-                    Err(e) => debug!("upgrade error for {}: {}", authority, e),
-                };
-            });
-        } else {
-            tokio::task::spawn(async move {
-                let remote_addr = host_addr(req.uri()).unwrap();
-                let upgraded = hyper::upgrade::on(req).await.unwrap();
-                tunnel(upgraded, remote_addr).await
-            });
+        self.events.emit(|| Event::Connect {
+            client_addr: self.client_addr,
+            authority: authority.to_string(),
+        });
+        let intercept = self
+            .mitm_policy
+            .should_intercept(&self.new_ctx(), authority.host(), self.client_addr)
+            .await;
+        // an intercepted CONNECT is decided again by the SNI in `serve_tls`
+        if !intercept {
+            self.emit_mitm_decision(authority.host(), false);
         }
+        tokio::task::spawn(async move {
+            let upgraded = match hyper::upgrade::on(req).await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    debug!("{authority}: {}", Error::Upgrade(err));
+                    return;
+                }
+            };
+
+            if intercept {
+                self.serve_tls(upgraded, Some(authority)).await;
+            } else {
+                self.tunnel(upgraded, authority.to_string()).await;
+            }
+        });
         Ok(Response::new(Body::empty()))
     }
 
-    pub async fn serve_tls<IO: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    async fn process_reverse(
         self,
+        mut req: Request<Body>,
+        reverse: ReverseProxy,
+        scheme: Scheme,
+    ) -> Result<Response<Body>, Infallible> {
+        let headers = req.headers_mut();
+        if let Some(host) = headers.remove(header::HOST) {
+            headers.insert(HeaderName::from_static("x-forwarded-host"), host);
+        }
+        headers.insert(
+            HeaderName::from_static("x-forwarded-proto"),
+            HeaderValue::from_str(scheme.as_str()).unwrap(),
+        );
+        if let Some(client_addr) = self.client_addr {
+            headers.append(
+                HeaderName::from_static("x-forwarded-for"),
+                HeaderValue::from_str(&client_addr.ip().to_string()).unwrap(),
+            );
+        }
+
+        // rewrite to the backend, `process_request` builds the uri from `Host`
+        let authority = reverse.backend.authority().unwrap().as_str();
+        headers.insert(header::HOST, HeaderValue::from_str(authority).unwrap());
+
+        self.process_request(req, reverse.scheme()).await
+    }
+
+    pub async fn serve_conn<IO: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        self,
+        mut stream: IO,
+    ) {
+        let mut tls_content_type = [0; 1];
+        if stream.read_exact(&mut tls_content_type).await.is_err() {
+            return;
+        }
+        let stream = PrefixedReaderWriter::new(stream, tls_content_type.to_vec());
+
+        if let ListenerMode::Reverse(ref reverse) = self.mode {
+            let reverse = reverse.clone();
+            if tls_content_type[0] <= 0x40 {
+                self.serve_reverse_tls(stream, reverse).await;
+            } else if let Err(e) = self
+                .serve_reverse_stream(stream, reverse, Scheme::HTTP)
+                .await
+            {
+                debug!("reverse proxy: {}", e);
+            }
+        } else if tls_content_type[0] <= 0x40 {
+            // ASCII < 'A', assuming tls
+            if self.auth.is_some() {
+                // transparent tls carries no credentials
+                debug!("refuse unauthenticated tls connection");
+                return;
+            }
+            self.serve_tls(stream, None).await;
+        } else {
+            // assuming http
+            _ = self.serve_stream(stream).await;
+        }
+    }
+
+    /// Serve a TLS connection, `authority` is the target of the CONNECT request if any.
+    pub async fn serve_tls<IO: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        mut self,
         mut stream: IO,
+        authority: Option<Authority>,
     ) {
-        // Read SNI hostname.
+        // Read ClientHello for the SNI hostname.
         let mut recording_reader = RecordingBufReader::new(&mut stream);
         let reader = HandshakeRecordReader::new(&mut recording_reader);
         pin!(reader);
-        let sni_hostname = tokio::time::timeout(
-            Duration::from_secs(5),
-            read_sni_host_name_from_client_hello(reader),
-        )
-        .await
-        .unwrap()
-        .unwrap();
-
+        let client_hello =
+            match with_timeout(self.timeouts.tls_handshake, read_client_hello(reader)).await {
+                Ok(Ok(client_hello)) => Some(client_hello),
+                Ok(Err(err)) => {
+                    debug!("{:?}: {}", self.client_addr, Error::Sni(err));
+                    None
+                }
+                Err(_) => {
+                    debug!("{:?}: {}", self.client_addr, Error::Timeout("reading SNI"));
+                    return;
+                }
+            };
         let read_buf = recording_reader.buf();
         let client_stream = PrefixedReaderWriter::new(stream, read_buf);
 
-        if !self.mitm_filter.filter(&sni_hostname).await {
-            let remote_addr = format!("{sni_hostname}:443");
-            tokio::task::spawn(async move { tunnel(client_stream, remote_addr).await });
+        let Some(client_hello) = client_hello else {
+            if let Some(authority) = authority {
+                info!("Unreadable ClientHello for {authority}, tunnel it");
+                self.emit_mitm_decision(authority.host(), false);
+                self.tunnel(client_stream, authority.to_string()).await;
+            }
+            return;
+        };
+        // the inner ClientHello is for the real host, the outer one for the public name, so
+        // the forged certificate can't match the name the client verifies. GREASE ECH looks
+        // the same, only a CONNECT authority other than the public name tells them apart.
+        if let (true, Some(sni)) = (client_hello.offers_ech(), &client_hello.server_name) {
+            let remote_addr = match authority {
+                Some(ref authority) if !sni.eq_ignore_ascii_case(authority.host()) => {
+                    Some(authority.to_string())
+                }
+                Some(_) => None,
+                None => Some(format!("{sni}:443")),
+            };
+            if let Some(remote_addr) = remote_addr {
+                info!(
+                    "Encrypted Client Hello via {sni}, tunnel it to {remote_addr} as it can't be \
+                     intercepted"
+                );
+                self.emit_mitm_decision(sni, false);
+                self.tunnel(client_stream, remote_addr).await;
+                return;
+            }
+        }
+        let sni_hostname = match (&client_hello.server_name, &authority) {
+            (Some(sni), _) => sni.clone(),
+            // e.g. clients connecting to an IP address
+            (None, Some(authority)) => {
+                debug!("No SNI for {authority}, use the CONNECT authority");
+                authority.host().to_owned()
+            }
+            (None, None) => {
+                let err = io::Error::new(io::ErrorKind::InvalidData, "no server name");
+                debug!("{:?}: {}", self.client_addr, Error::Sni(err));
+                return;
+            }
+        };
+        debug!(
+            "{sni_hostname} ja3={} ja4={}",
+            client_hello.ja3, client_hello.ja4
+        );
+
+        self.events.emit(|| Event::Sni {
+            client_addr: self.client_addr,
+            host: sni_hostname.clone(),
+            ja3: client_hello.ja3.clone(),
+            ja4: client_hello.ja4.clone(),
+        });
+        let has_sni = client_hello.server_name.is_some();
+        self.client_hello = Some(Arc::new(client_hello));
+
+        let passthrough = self
+            .passthrough
+            .as_ref()
+            .is_some_and(|passthrough| passthrough.is_passthrough(&sni_hostname));
+        if passthrough {
+            debug!("[Passthrough] tunnel {sni_hostname}");
+        }
+        let intercept = !passthrough
+            && self
+                .mitm_policy
+                .should_intercept(&self.new_ctx(), &sni_hostname, self.client_addr)
+                .await;
+        self.emit_mitm_decision(&sni_hostname, intercept);
+        if !intercept {
+            let remote_addr = match authority {
+                Some(authority) => authority.to_string(),
+                None => format!("{sni_hostname}:443"),
+            };
+            tokio::task::spawn(async move { self.tunnel(client_stream, remote_addr).await });
             return;
         }
 
-        let server_config = self.ca.clone().gen_server_config();
+        let server_config = if has_sni {
+            self.ca.clone().gen_server_config()
+        } else {
+            self.ca
+                .clone()
+                .gen_server_config_with_default(&sni_hostname)
+        };
 
-        match TlsAcceptor::from(server_config).accept(client_stream).await {
-            Ok(stream) => {
-                if let Err(e) = Http::new()
-                    .http1_preserve_header_case(true)
-                    .http1_title_case_headers(true)
+        let start = Instant::now();
+        let accept = TlsAcceptor::from(server_config).accept(client_stream);
+        let accepted = with_timeout(self.timeouts.tls_handshake, accept).await;
+        self.emit_tls_handshake(&sni_hostname, start, &accepted);
+        if let (Some(passthrough), Ok(accepted)) = (&self.passthrough, &accepted) {
+            match accepted {
+                Ok(_) => passthrough.record_success(&sni_hostname),
+                Err(err) => passthrough.record_failure(&sni_hostname, err),
+            }
+        }
+        match accepted {
+            Ok(Ok(stream)) => {
+                if let Err(e) = self
+                    .http()
                     .serve_connection(
                         stream,
                         service_fn(|req| self.clone().process_request(req, Scheme::HTTPS)),
@@ -224,24 +448,180 @@ where
                     }
                 }
             }
-            Err(err) => {
+            Ok(Err(err)) => {
+                error!("Tls accept failed for {sni_hostname}: {err}")
+            }
+            Err(_) => {
+                debug!("{sni_hostname}: {}", Error::Timeout("tls handshake"))
+            }
+        }
+    }
+
+    async fn serve_reverse_tls<IO: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        self,
+        stream: IO,
+        reverse: ReverseProxy,
+    ) {
+        let server_config = match reverse.server_config {
+            Some(ref server_config) => server_config.clone(),
+            None => self
+                .ca
+                .clone()
+                .gen_server_config_with_default(reverse.backend.host().unwrap_or_default()),
+        };
+
+        let start = Instant::now();
+        let accept = TlsAcceptor::from(server_config).accept(stream);
+        let accepted = with_timeout(self.timeouts.tls_handshake, accept).await;
+        self.emit_tls_handshake(reverse.backend.host().unwrap_or_default(), start, &accepted);
+        match accepted {
+            Ok(Ok(stream)) => {
+                if let Err(e) = self
+                    .serve_reverse_stream(stream, reverse, Scheme::HTTPS)
+                    .await
+                {
+                    debug!("reverse proxy: {}", e);
+                }
+            }
+            Ok(Err(err)) => {
                 error!("Tls accept failed: {err}")
             }
+            Err(_) => {
+                debug!(
+                    "{:?}: {}",
+                    self.client_addr,
+                    Error::Timeout("tls handshake")
+                )
+            }
         }
     }
 
+    async fn serve_reverse_stream<S>(
+        self,
+        stream: S,
+        reverse: ReverseProxy,
+        scheme: Scheme,
+    ) -> Result<(), hyper::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.http()
+            .serve_connection(
+                stream,
+                service_fn(|req| {
+                    self.clone()
+                        .process_reverse(req, reverse.clone(), scheme.clone())
+                }),
+            )
+            .with_upgrades()
+            .await
+    }
+
     pub async fn serve_stream<S>(self, stream: S) -> Result<(), hyper::Error>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        Http::new()
-            .http1_preserve_header_case(true)
-            .http1_title_case_headers(true)
+        self.http()
             .serve_connection(stream, service_fn(|req| self.clone().proxy_req(req)))
             .with_upgrades()
             .await
     }
 
+    /// Connect to `addr` and copy data between it and the client until either side closes.
+    async fn tunnel<A>(&self, client_stream: A, addr: String)
+    where
+        A: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start = Instant::now();
+        // outside of the copy to keep the counts when it fails
+        let mut client_stream = Counted::new(client_stream);
+        let copied = async {
+            let mut server =
+                with_timeout(self.timeouts.connect, TcpStream::connect(&addr)).await??;
+            if let Some(version) = self.send_proxy_protocol {
+                let peer_addr = server.peer_addr()?;
+                write_header(&mut server, version, self.client_addr, peer_addr).await?;
+            }
+            // idle tunnels are closed by the client stream's idle timeout
+            with_timeout(
+                self.timeouts.tunnel,
+                tokio::io::copy_bidirectional(&mut client_stream, &mut server),
+            )
+            .await??;
+            Ok::<_, io::Error>(())
+        }
+        .await;
+
+        if let Err(ref err) = copied {
+            debug!("tunnel to {addr} closed: {err}");
+        }
+        self.events.emit(|| Event::TunnelClose {
+            client_addr: self.client_addr,
+            host: addr,
+            sent: client_stream.read,
+            received: client_stream.written,
+            duration: start.elapsed(),
+            error: copied.err().map(|err| err.to_string()),
+        });
+    }
+
+    fn emit_mitm_decision(&self, host: &str, intercept: bool) {
+        self.events.emit(|| Event::MitmDecision {
+            client_addr: self.client_addr,
+            host: host.to_owned(),
+            intercept,
+        });
+    }
+
+    fn emit_tls_handshake<S, E: std::fmt::Display, T>(
+        &self,
+        host: &str,
+        start: Instant,
+        accepted: &Result<Result<S, E>, T>,
+    ) {
+        self.events.emit(|| match accepted {
+            Ok(Ok(_)) => Event::TlsHandshake {
+                client_addr: self.client_addr,
+                host: host.to_owned(),
+                duration: start.elapsed(),
+            },
+            Ok(Err(err)) => Event::TlsHandshakeFailed {
+                client_addr: self.client_addr,
+                host: host.to_owned(),
+                duration: start.elapsed(),
+                error: err.to_string(),
+            },
+            Err(_) => Event::TlsHandshakeFailed {
+                client_addr: self.client_addr,
+                host: host.to_owned(),
+                duration: start.elapsed(),
+                error: Error::Timeout("tls handshake").to_string(),
+            },
+        });
+    }
+
+    fn new_ctx(&self) -> HttpContext<D> {
+        HttpContext {
+            uri: None,
+            username: self.username.clone(),
+            client_addr: self.client_addr,
+            client_hello: self.client_hello.clone(),
+            should_modify_response: false,
+            ..Default::default()
+        }
+    }
+
+    fn http(&self) -> Http {
+        let mut http = Http::new();
+        http.http1_preserve_header_case(true)
+            .http1_title_case_headers(true)
+            .http1_keep_alive(self.keep_alive.client);
+        if let Some(timeout) = self.timeouts.header_read {
+            http.http1_header_read_timeout(timeout);
+        }
+        http
+    }
+
     fn get_cert_res(&self) -> hyper::Response<Body> {
         Response::builder()
             .header(
@@ -255,6 +635,14 @@ where
     }
 }
 
+fn proxy_auth_required_res() -> Response<Body> {
+    Response::builder()
+        .status(http::StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .header(header::PROXY_AUTHENTICATE, "Basic realm=\"Good-MITM\"")
+        .body(Body::empty())
+        .unwrap()
+}
+
 fn allow_all_cros(res: &mut Response<Body>) {
     let header_mut = res.headers_mut();
     let all = HeaderValue::from_str("*").unwrap();
@@ -263,15 +651,9 @@ fn allow_all_cros(res: &mut Response<Body>) {
     header_mut.insert(http::header::ACCESS_CONTROL_ALLOW_METHODS, all);
 }
 
-fn host_addr(uri: &http::Uri) -> Option<String> {
-    uri.authority().map(|auth| auth.to_string())
-}
-
-async fn tunnel<A>(mut client_stream: A, addr: String) -> std::io::Result<()>
-where
-    A: AsyncRead + AsyncWrite + Unpin,
-{
-    let mut server = TcpStream::connect(addr).await?;
-    tokio::io::copy_bidirectional(&mut client_stream, &mut server).await?;
-    Ok(())
+fn log_error(err: &Error) {
+    match err.source() {
+        Some(source) => warn!("{err}: {source}"),
+        None => warn!("{err}"),
+    }
 }
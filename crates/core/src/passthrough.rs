@@ -0,0 +1,177 @@
+use log::info;
+use rustls::AlertDescription;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Tunnels hosts whose clients keep rejecting the forged certificate, e.g. apps with
+/// certificate pinning, instead of failing every connection.
+///
+/// Clones share the learned hosts.
+#[derive(Clone)]
+pub struct Passthrough {
+    threshold: u32,
+    cooldown: Duration,
+    hosts: Arc<Mutex<HashMap<String, HostState>>>,
+}
+
+/// Hosts tracked at most, expired ones are dropped when it is reached.
+const MAX_HOSTS: usize = 4096;
+
+struct HostState {
+    /// Consecutive rejected handshakes, forgotten `cooldown` after the last one.
+    failures: u32,
+    last_failure: Instant,
+    until: Option<Instant>,
+}
+
+impl HostState {
+    fn is_expired(&self, now: Instant, cooldown: Duration) -> bool {
+        match self.until {
+            Some(until) => until <= now,
+            None => self.last_failure + cooldown <= now,
+        }
+    }
+}
+
+impl Default for Passthrough {
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(600))
+    }
+}
+
+impl Passthrough {
+    /// Tunnel a host for `cooldown` after `threshold` consecutive rejected handshakes.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            hosts: Default::default(),
+        }
+    }
+
+    pub fn is_passthrough(&self, host: &str) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        match hosts.get(host).and_then(|state| state.until) {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                hosts.remove(host);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Hosts currently tunneled, with the remaining cooldown.
+    pub fn list(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .iter()
+            .filter_map(|(host, state)| {
+                let until = state.until.filter(|until| *until > now)?;
+                Some((host.clone(), until - now))
+            })
+            .collect()
+    }
+
+    /// Intercept `host` again.
+    pub fn remove(&self, host: &str) -> bool {
+        self.hosts.lock().unwrap().remove(host).is_some()
+    }
+
+    pub(crate) fn record_success(&self, host: &str) {
+        self.hosts.lock().unwrap().remove(host);
+    }
+
+    pub(crate) fn record_failure(&self, host: &str, err: &io::Error) {
+        if !is_certificate_rejected(err) {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.len() >= MAX_HOSTS && !hosts.contains_key(host) {
+            hosts.retain(|_, state| !state.is_expired(now, self.cooldown));
+            if hosts.len() >= MAX_HOSTS {
+                return;
+            }
+        }
+        let state = hosts.entry(host.to_owned()).or_insert(HostState {
+            failures: 0,
+            last_failure: now,
+            until: None,
+        });
+        if state.is_expired(now, self.cooldown) {
+            state.failures = 0;
+            state.until = None;
+        }
+        state.failures += 1;
+        state.last_failure = now;
+        if state.failures >= self.threshold {
+            info!(
+                "[Passthrough] {host} rejected the certificate {} times, tunnel it for {:?}",
+                state.failures, self.cooldown
+            );
+            state.failures = 0;
+            state.until = Some(now + self.cooldown);
+        }
+    }
+}
+
+fn is_certificate_rejected(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<rustls::Error>()),
+        Some(
+            rustls::Error::AlertReceived(
+                AlertDescription::UnknownCA
+                    | AlertDescription::BadCertificate
+                    | AlertDescription::CertificateUnknown
+                    | AlertDescription::UnsupportedCertificate
+            )
+            // OpenSSL clients send the alert unencrypted after rejecting a TLS 1.3 certificate
+            | rustls::Error::DecryptError
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected() -> io::Error {
+        let err = rustls::Error::AlertReceived(AlertDescription::UnknownCA);
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    #[test]
+    fn tunnels_after_threshold() {
+        let passthrough = Passthrough::new(2, Duration::from_secs(60));
+        passthrough.record_failure("a.com", &rejected());
+        assert!(!passthrough.is_passthrough("a.com"));
+        passthrough.record_failure("a.com", &rejected());
+        assert!(passthrough.is_passthrough("a.com"));
+    }
+
+    #[test]
+    fn forgets_old_failures() {
+        let passthrough = Passthrough::new(2, Duration::from_millis(20));
+        passthrough.record_failure("a.com", &rejected());
+        std::thread::sleep(Duration::from_millis(30));
+        passthrough.record_failure("a.com", &rejected());
+        assert!(!passthrough.is_passthrough("a.com"));
+    }
+
+    #[test]
+    fn drops_expired_hosts_when_full() {
+        let passthrough = Passthrough::new(2, Duration::ZERO);
+        for i in 0..MAX_HOSTS * 2 {
+            passthrough.record_failure(&format!("{i}.com"), &rejected());
+        }
+        assert!(passthrough.hosts.lock().unwrap().len() <= MAX_HOSTS);
+    }
+}
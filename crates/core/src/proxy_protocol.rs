@@ -0,0 +1,300 @@
+// PROXY protocol, see https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+/// Bounds reading the header unless the header read timeout is set.
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// Reads a v1 or v2 header and returns the source address it carries.
+///
+/// Only the header is consumed from `reader`. `None` is returned for `LOCAL` and
+/// `UNKNOWN` connections, which carry no usable address.
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0; 5];
+    reader.read_exact(&mut prefix).await?;
+
+    if &prefix == b"PROXY" {
+        read_v1(reader).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(reader).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header not utf8"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 address"))?;
+            let port: u16 = src_port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut rest = [0; 11];
+    reader.read_exact(&mut rest).await?;
+    if rest[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("invalid PROXY protocol v2 signature"));
+    }
+
+    let ver_cmd = rest[7];
+    let family = rest[8];
+    let len = u16::from_be_bytes([rest[9], rest[10]]) as usize;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // LOCAL command, e.g. health checks from the balancer itself
+    if ver_cmd & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 if payload.len() >= 12 => {
+            let ip: [u8; 4] = payload[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 if payload.len() >= 36 => {
+            let ip: [u8; 16] = payload[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Writes a header announcing a connection from `src` to `dst`.
+pub(crate) async fn write_header<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    version: ProxyProtocolVersion,
+    src: Option<SocketAddr>,
+    dst: SocketAddr,
+) -> io::Result<()> {
+    let addrs = src.map(|src| same_family(src, dst));
+    let header = match version {
+        ProxyProtocolVersion::V1 => encode_v1(addrs),
+        ProxyProtocolVersion::V2 => encode_v2(addrs),
+    };
+    writer.write_all(&header).await
+}
+
+fn encode_v1(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    match addrs {
+        Some((src, dst)) => {
+            let proto = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {proto} {} {} {} {}\r\n",
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        None => b"PROXY UNKNOWN\r\n".to_vec(),
+    }
+}
+
+fn encode_v2(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    match addrs {
+        Some((SocketAddr::V4(src), SocketAddr::V4(dst))) => {
+            header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        Some((src, dst)) => {
+            let ip6 = |addr: SocketAddr| match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+            header.extend_from_slice(&ip6(src).octets());
+            header.extend_from_slice(&ip6(dst).octets());
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        None => header.extend_from_slice(&[0x20, 0x00, 0, 0]),
+    }
+    header
+}
+
+/// Both addresses of a header must share the family, map IPv4 into IPv6 if they differ.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let src = SocketAddr::new(src.ip().to_canonical(), src.port());
+    let dst = SocketAddr::new(dst.ip().to_canonical(), dst.port());
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(ip), IpAddr::V6(_)) => {
+            (SocketAddr::new(ip.to_ipv6_mapped().into(), src.port()), dst)
+        }
+        (IpAddr::V6(_), IpAddr::V4(ip)) => {
+            (src, SocketAddr::new(ip.to_ipv6_mapped().into(), dst.port()))
+        }
+        _ => (src, dst),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_header(&mut header).await
+    }
+
+    async fn round_trip(
+        version: ProxyProtocolVersion,
+        src: Option<SocketAddr>,
+        dst: SocketAddr,
+    ) -> Option<SocketAddr> {
+        let mut header = vec![];
+        write_header(&mut header, version, src, dst).await.unwrap();
+        read(&header).await.unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for (src, dst, expected) in [
+                ("1.2.3.4:5678", "5.6.7.8:443", "1.2.3.4:5678"),
+                (
+                    "[2001:db8::1]:5678",
+                    "[2001:db8::2]:443",
+                    "[2001:db8::1]:5678",
+                ),
+                // mixed families are mapped into IPv6
+                ("1.2.3.4:5678", "[2001:db8::2]:443", "[::ffff:1.2.3.4]:5678"),
+            ] {
+                let read = round_trip(version, Some(addr(src)), addr(dst)).await;
+                assert_eq!(read, Some(addr(expected)), "{version:?} {src} {dst}");
+            }
+            assert_eq!(round_trip(version, None, addr("5.6.7.8:443")).await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn consumes_only_the_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 1.2.3.4 5.6.7.8 5678 443\r\nGET / HTTP/1.1\r\n";
+        let src = read_header(&mut stream).await.unwrap();
+        assert_eq!(src, Some(addr("1.2.3.4:5678")));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut header = encode_v2(Some((addr("1.2.3.4:5678"), addr("5.6.7.8:443"))));
+        header.extend_from_slice(b"rest");
+        let mut stream = header.as_slice();
+        read_header(&mut stream).await.unwrap();
+        assert_eq!(stream, b"rest");
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        let header = b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n";
+        assert_eq!(read(header).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // LOCAL with an address block, which must be ignored
+        header.extend_from_slice(&[0x20, 0x11, 0, 12]);
+        header.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 0, 1, 0, 2]);
+        assert_eq!(read(&header).await.unwrap(), None);
+        assert_eq!(read(&encode_v2(None)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_unspecified_family() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x00, 0, 0]);
+        assert_eq!(read(&header).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed() {
+        for header in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 5678\r\n",
+            b"PROXY TCP4 1.2.3.x 5.6.7.8 5678 443\r\n",
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 70000 443\r\n",
+            b"PROXY UDP4 1.2.3.4 5.6.7.8 5678 443\r\n",
+            b"PROXY TCP4 \xff.2.3.4 5.6.7.8 5678 443\r\n",
+            b"\r\n\r\n\0\r\nQUIX\n\x21\x11\0\0",
+        ] {
+            let err = read(header).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{header:?}");
+        }
+
+        let too_long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        let err = read(too_long.as_bytes()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // version 1 in the binary format
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        let err = read(&header).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let v2 = encode_v2(Some((addr("1.2.3.4:5678"), addr("5.6.7.8:443"))));
+        for header in [
+            &b"PRO"[..],
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 5678 443",
+            &v2[..10],
+            &v2[..v2.len() - 1],
+        ] {
+            let err = read(header).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{header:?}");
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use log::warn;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::lookup_host, time::timeout};
+
+/// How long resolved addresses, or a failure to resolve, are cached.
+const TTL: Duration = Duration::from_secs(60);
+/// Hosts cached at most, expired ones are dropped when it is reached.
+const MAX_HOSTS: usize = 4096;
+
+/// Resolves hosts for matching CIDRs, giving up after a timeout and caching the addresses
+/// for a minute.
+///
+/// Clones share the cache, so the MITM list and the handler see the same addresses.
+#[derive(Clone)]
+pub struct HostResolver {
+    cache: Arc<Mutex<HashMap<String, Resolved>>>,
+    timeout: Duration,
+}
+
+struct Resolved {
+    ips: Vec<IpAddr>,
+    at: Instant,
+}
+
+impl Default for HostResolver {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl HostResolver {
+    /// Give up after `timeout`, like connecting upstream, or after 5 seconds.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            cache: Default::default(),
+            timeout: timeout.unwrap_or(Duration::from_secs(5)),
+        }
+    }
+
+    /// Addresses of `host`, empty if it failed to resolve in time. IP hosts are returned as is.
+    pub async fn resolve(&self, host: &str) -> Vec<IpAddr> {
+        if let Some(ip) = parse_ip(host) {
+            return vec![ip.to_canonical()];
+        }
+        let now = Instant::now();
+        if let Some(resolved) = self.cache.lock().unwrap().get(host) {
+            if resolved.at + TTL > now {
+                return resolved.ips.clone();
+            }
+        }
+
+        let ips = match timeout(self.timeout, lookup_host((host, 0))).await {
+            Ok(Ok(addrs)) => addrs.map(|addr| addr.ip().to_canonical()).collect(),
+            Ok(Err(err)) => {
+                warn!("failed to resolve {host}: {err}");
+                vec![]
+            }
+            Err(_) => {
+                warn!("resolving {host} timed out");
+                vec![]
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_HOSTS && !cache.contains_key(host) {
+            cache.retain(|_, r| r.at + TTL > now);
+        }
+        if cache.len() < MAX_HOSTS || cache.contains_key(host) {
+            let entry = Resolved {
+                ips: ips.clone(),
+                at: now,
+            };
+            cache.insert(host.to_owned(), entry);
+        }
+        ips
+    }
+}
+
+/// The IP of an IP host, IPv6 with or without brackets.
+pub(crate) fn parse_ip(host: &str) -> Option<IpAddr> {
+    host.trim_matches(&['[', ']'][..]).parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn caches_addresses() {
+        let resolver = HostResolver::default();
+        let cache = |host: &str, at| {
+            let ips = vec!["10.0.0.1".parse().unwrap()];
+            resolver
+                .cache
+                .lock()
+                .unwrap()
+                .insert(host.to_owned(), Resolved { ips, at });
+        };
+        cache("fresh.invalid", Instant::now());
+        assert_eq!(
+            resolver.resolve("fresh.invalid").await,
+            ["10.0.0.1".parse::<IpAddr>().unwrap()]
+        );
+
+        cache("stale.invalid", Instant::now() - TTL);
+        assert!(resolver.resolve("stale.invalid").await.is_empty());
+        let cache = resolver.cache.lock().unwrap();
+        assert!(cache.get("stale.invalid").unwrap().ips.is_empty());
+    }
+
+    #[tokio::test]
+    async fn returns_ip_hosts() {
+        let resolver = HostResolver::default();
+        assert_eq!(
+            resolver.resolve("[::ffff:127.0.0.1]").await,
+            [IpAddr::from([127, 0, 0, 1])]
+        );
+        assert!(resolver.cache.lock().unwrap().is_empty());
+    }
+}
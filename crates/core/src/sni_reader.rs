@@ -1,4 +1,5 @@
 /// from https://github.com/branlwyd/rspd/blob/master/src/main.rs
+use crate::client_hello::ClientHello;
 use byteorder::{ByteOrder, NetworkEndian};
 use pin_project::pin_project;
 use std::{
@@ -8,10 +9,7 @@ use std::{
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::{
-    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, Error, ReadBuf},
-    pin,
-};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, Error, ReadBuf};
 
 #[pin_project]
 pub struct RecordingBufReader<R: AsyncRead> {
@@ -195,6 +193,9 @@ impl<R: AsyncRead> AsyncRead for HandshakeRecordReader<R> {
                     let mut buf = ReadBuf::new(&mut buf[..]);
                     buf.advance(*bytes_read);
                     match this.reader.as_mut().poll_read(cx, &mut buf) {
+                        Poll::Ready(Ok(())) if buf.filled().len() == *bytes_read => {
+                            return Poll::Ready(Err(truncated_header()));
+                        }
                         Poll::Ready(Ok(())) => {
                             *bytes_read = buf.filled().len();
                             if *bytes_read == 2 {
@@ -211,6 +212,9 @@ impl<R: AsyncRead> AsyncRead for HandshakeRecordReader<R> {
                     let mut buf = ReadBuf::new(&mut backing_array[..]);
                     buf.advance(*bytes_read);
                     match this.reader.as_mut().poll_read(cx, &mut buf) {
+                        Poll::Ready(Ok(())) if buf.filled().len() == *bytes_read => {
+                            return Poll::Ready(Err(truncated_header()));
+                        }
                         Poll::Ready(Ok(())) => {
                             *bytes_read = buf.filled().len();
                             if *bytes_read == 2 {
@@ -261,9 +265,14 @@ impl<R: AsyncRead> AsyncRead for HandshakeRecordReader<R> {
     }
 }
 
-pub async fn read_sni_host_name_from_client_hello<R: AsyncRead>(
-    mut reader: Pin<&mut R>,
-) -> io::Result<String> {
+fn truncated_header() -> io::Error {
+    io::Error::new(ErrorKind::UnexpectedEof, "truncated record header")
+}
+
+/// ClientHellos are a few KiB at most, even with post-quantum key shares.
+const MAX_CLIENT_HELLO_LEN: u32 = 1 << 16;
+
+pub async fn read_client_hello<R: AsyncRead>(mut reader: Pin<&mut R>) -> io::Result<ClientHello> {
     // Handshake message type.
     const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
     let typ = reader.read_u8().await?;
@@ -279,80 +288,16 @@ pub async fn read_sni_host_name_from_client_hello<R: AsyncRead>(
 
     // Handshake message length.
     let len = read_u24(reader.as_mut()).await?;
-    let reader = reader.take(len.into());
-    pin!(reader);
-
-    // ProtocolVersion (2 bytes) & random (32 bytes).
-    skip(reader.as_mut(), 34).await?;
-
-    // Session ID (u8-length vec), cipher suites (u16-length vec), compression methods (u8-length vec).
-    skip_vec_u8(reader.as_mut()).await?;
-    skip_vec_u16(reader.as_mut()).await?;
-    skip_vec_u8(reader.as_mut()).await?;
-
-    // Extensions.
-    let ext_len = reader.read_u16().await?;
-    let new_limit = min(reader.limit(), ext_len.into());
-    reader.set_limit(new_limit);
-    loop {
-        // Extension type & length.
-        let ext_typ = reader.read_u16().await?;
-        let ext_len = reader.read_u16().await?;
-
-        const EXTENSION_TYPE_SNI: u16 = 0;
-        if ext_typ != EXTENSION_TYPE_SNI {
-            skip(reader.as_mut(), ext_len.into()).await?;
-            continue;
-        }
-        let new_limit = min(reader.limit(), ext_len.into());
-        reader.set_limit(new_limit);
-
-        // ServerNameList length.
-        let snl_len = reader.read_u16().await?;
-        let new_limit = min(reader.limit(), snl_len.into());
-        reader.set_limit(new_limit);
-
-        // ServerNameList.
-        loop {
-            // NameType & length.
-            let name_typ = reader.read_u8().await?;
-
-            const NAME_TYPE_HOST_NAME: u8 = 0;
-            if name_typ != NAME_TYPE_HOST_NAME {
-                skip_vec_u16(reader.as_mut()).await?;
-                continue;
-            }
-
-            let name_len = reader.read_u16().await?;
-            let new_limit = min(reader.limit(), name_len.into());
-            reader.set_limit(new_limit);
-            let mut name_buf = vec![0; name_len.into()];
-            reader.read_exact(&mut name_buf).await?;
-            return String::from_utf8(name_buf)
-                .map_err(|err| io::Error::new(ErrorKind::InvalidData, err));
-        }
-    }
-}
-
-async fn skip<R: AsyncRead>(reader: Pin<&mut R>, len: u64) -> io::Result<()> {
-    let bytes_read = io::copy(&mut reader.take(len), &mut io::sink()).await?;
-    if bytes_read < len {
+    if len > MAX_CLIENT_HELLO_LEN {
         return Err(io::Error::new(
-            ErrorKind::UnexpectedEof,
-            format!("skip read {} < {} bytes", bytes_read, len),
+            ErrorKind::InvalidData,
+            format!("ClientHello too long ({} bytes)", len),
         ));
     }
-    Ok(())
-}
-
-async fn skip_vec_u8<R: AsyncRead>(mut reader: Pin<&mut R>) -> io::Result<()> {
-    let sz = reader.read_u8().await?;
-    skip(reader.as_mut(), sz.into()).await
-}
 
-async fn skip_vec_u16<R: AsyncRead>(mut reader: Pin<&mut R>) -> io::Result<()> {
-    let sz = reader.read_u16().await?;
-    skip(reader.as_mut(), sz.into()).await
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body).await?;
+    ClientHello::parse(&body)
 }
 
 async fn read_u24<R: AsyncRead>(mut reader: Pin<&mut R>) -> io::Result<u32> {
@@ -0,0 +1,159 @@
+use pin_project::pin_project;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{error::Elapsed, sleep, Instant, Sleep},
+};
+
+/// Timeouts applied to client and upstream connections, `None` disables one.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Connecting to upstream servers and tunnel targets.
+    pub connect: Option<Duration>,
+    /// Reading the ClientHello and completing the TLS handshake with clients.
+    pub tls_handshake: Option<Duration>,
+    /// Reading request headers from clients, and PROXY protocol headers, 5s for those if
+    /// unset.
+    pub header_read: Option<Duration>,
+    /// Waiting for response headers from upstream servers.
+    pub upstream_response: Option<Duration>,
+    /// Closing client connections and tunnels without any traffic.
+    pub idle: Option<Duration>,
+    /// Maximum lifetime of a tunnel.
+    pub tunnel: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: None,
+            tls_handshake: Some(Duration::from_secs(5)),
+            header_read: None,
+            upstream_response: None,
+            idle: None,
+            tunnel: None,
+        }
+    }
+}
+
+/// Keep-alive of client connections and pooling of upstream connections.
+#[derive(Clone, Copy, Debug)]
+pub struct KeepAlive {
+    /// Keep HTTP/1 client connections open between requests.
+    pub client: bool,
+    /// TCP keep-alive for upstream connections.
+    pub tcp: Option<Duration>,
+    /// Idle upstream connections kept per host.
+    pub pool_max_idle_per_host: usize,
+    /// Close pooled upstream connections idle longer than this.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Interval of HTTP/2 pings to upstream servers.
+    pub http2_interval: Option<Duration>,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            client: true,
+            tcp: None,
+            pool_max_idle_per_host: usize::MAX,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            http2_interval: None,
+        }
+    }
+}
+
+/// Fails reads and writes once the stream has seen no traffic for the idle timeout.
+#[pin_project]
+pub(crate) struct IdleTimeout<IO> {
+    #[pin]
+    inner: IO,
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<IO> IdleTimeout<IO> {
+    pub fn new(inner: IO, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: timeout.map(|timeout| Box::pin(sleep(timeout))),
+        }
+    }
+}
+
+fn poll_idle(
+    sleep: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Option<Duration>,
+    cx: &mut Context<'_>,
+    active: bool,
+) -> Poll<io::Error> {
+    match (sleep, timeout) {
+        (Some(sleep), Some(timeout)) if active => {
+            sleep.as_mut().reset(Instant::now() + timeout);
+            Poll::Pending
+        }
+        (Some(sleep), _) => sleep
+            .as_mut()
+            .poll(cx)
+            .map(|_| io::Error::new(io::ErrorKind::TimedOut, "connection idle timeout")),
+        _ => Poll::Pending,
+    }
+}
+
+impl<IO: AsyncRead> AsyncRead for IdleTimeout<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let rslt = this.inner.poll_read(cx, buf);
+        let active = rslt.is_ready();
+        if let Poll::Ready(err) = poll_idle(this.sleep, *this.timeout, cx, active) {
+            return Poll::Ready(Err(err));
+        }
+        rslt
+    }
+}
+
+impl<IO: AsyncWrite> AsyncWrite for IdleTimeout<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let rslt = this.inner.poll_write(cx, buf);
+        let active = rslt.is_ready();
+        if let Poll::Ready(err) = poll_idle(this.sleep, *this.timeout, cx, active) {
+            return Poll::Ready(Err(err));
+        }
+        rslt
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Awaits `future`, giving up after `timeout` if one is set.
+pub(crate) async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await,
+        None => Ok(future.await),
+    }
+}
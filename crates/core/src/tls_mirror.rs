@@ -0,0 +1,142 @@
+use crate::client_hello::ClientHello;
+use rustls::{
+    client::ServerCertVerifier, ClientConfig, SupportedCipherSuite, SupportedKxGroup,
+    SupportedProtocolVersion, ALL_CIPHER_SUITES, ALL_KX_GROUPS, ALL_VERSIONS,
+};
+use std::sync::Arc;
+use wildmatch::WildMatch;
+
+/// Offer upstream servers what the client offered in its ClientHello, so the proxied
+/// connection looks like the client's own as far as rustls supports it.
+///
+/// Mirrored connections always use rustls.
+#[derive(Clone, Debug)]
+pub struct TlsMirror {
+    /// Wildcard patterns of the hosts to mirror.
+    pub hosts: Vec<String>,
+    pub alpn: bool,
+    /// Cipher suites and key exchange groups, in the client's order.
+    pub cipher_suites: bool,
+    pub versions: bool,
+}
+
+impl TlsMirror {
+    /// Mirror everything for hosts matching `hosts`.
+    pub fn new(hosts: Vec<String>) -> Self {
+        Self {
+            hosts,
+            alpn: true,
+            cipher_suites: true,
+            versions: true,
+        }
+    }
+
+    pub(crate) fn matcher(&self) -> Vec<WildMatch> {
+        self.hosts.iter().map(|host| WildMatch::new(host)).collect()
+    }
+
+    /// What to offer upstream for this client, limited to what rustls supports.
+    pub(crate) fn key(&self, hello: &ClientHello) -> MirrorKey {
+        let mut key = MirrorKey::default();
+        if self.cipher_suites {
+            key.cipher_suites = supported(&hello.cipher_suites, ALL_CIPHER_SUITES, |suite| {
+                suite.suite().get_u16()
+            });
+            key.kx_groups = supported(&hello.supported_groups, &ALL_KX_GROUPS, |group| {
+                group.name.get_u16()
+            });
+        }
+        if self.versions {
+            // the extension is TLS 1.3 only, older clients only send the legacy version
+            let versions = match hello.supported_versions.is_empty() {
+                true => vec![hello.version],
+                false => hello.supported_versions.clone(),
+            };
+            key.versions = supported(&versions, ALL_VERSIONS, |version| version.version.get_u16());
+        }
+        if self.alpn {
+            key.alpn = hello
+                .alpn
+                .iter()
+                .filter(|proto| {
+                    proto.as_slice() == b"http/1.1"
+                        || (cfg!(feature = "h2") && proto.as_slice() == b"h2")
+                })
+                .cloned()
+                .collect();
+        } else {
+            key.alpn = default_alpn();
+        }
+        key
+    }
+}
+
+/// The mirrored parts of a ClientHello, ids are kept in the client's order.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub(crate) struct MirrorKey {
+    cipher_suites: Vec<u16>,
+    kx_groups: Vec<u16>,
+    versions: Vec<u16>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl MirrorKey {
+    pub fn client_config(&self, verifier: Arc<dyn ServerCertVerifier>) -> ClientConfig {
+        let cipher_suites: Vec<SupportedCipherSuite> =
+            find(&self.cipher_suites, ALL_CIPHER_SUITES, |suite| {
+                suite.suite().get_u16()
+            });
+        let kx_groups: Vec<&'static SupportedKxGroup> =
+            find(&self.kx_groups, &ALL_KX_GROUPS, |group| {
+                group.name.get_u16()
+            });
+        let versions: Vec<&'static SupportedProtocolVersion> =
+            find(&self.versions, ALL_VERSIONS, |version| {
+                version.version.get_u16()
+            });
+
+        let builder = ClientConfig::builder()
+            .with_cipher_suites(or_all(&cipher_suites, ALL_CIPHER_SUITES))
+            .with_kx_groups(or_all(&kx_groups, &ALL_KX_GROUPS))
+            .with_protocol_versions(or_all(&versions, ALL_VERSIONS))
+            // e.g. only TLS 1.2 suites but only TLS 1.3 offered
+            .unwrap_or_else(|_| ClientConfig::builder().with_safe_defaults());
+
+        let mut config = builder
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+        config.alpn_protocols = self.alpn.clone();
+        config
+    }
+}
+
+fn default_alpn() -> Vec<Vec<u8>> {
+    if cfg!(feature = "h2") {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    }
+}
+
+/// Ids of `offered` known to rustls, in the offered order.
+fn supported<T>(offered: &[u16], all: &[T], id: impl Fn(&T) -> u16) -> Vec<u16> {
+    offered
+        .iter()
+        .copied()
+        .filter(|offered| all.iter().any(|t| id(t) == *offered))
+        .collect()
+}
+
+fn find<T: Copy>(ids: &[u16], all: &[T], id: impl Fn(&T) -> u16) -> Vec<T> {
+    ids.iter()
+        .filter_map(|i| all.iter().find(|t| id(t) == *i).copied())
+        .collect()
+}
+
+fn or_all<'a, T>(mirrored: &'a [T], all: &'a [T]) -> &'a [T] {
+    if mirrored.is_empty() {
+        all
+    } else {
+        mirrored
+    }
+}
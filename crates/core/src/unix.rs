@@ -0,0 +1,113 @@
+use hyper::{
+    client::connect::{Connected, Connection},
+    service::Service,
+    Uri,
+};
+use pin_project::pin_project;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UnixStream,
+};
+
+/// Maps upstream hosts to the Unix socket paths serving them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UnixRoutes(Arc<HashMap<String, PathBuf>>);
+
+impl UnixRoutes {
+    pub fn new(routes: HashMap<String, PathBuf>) -> Self {
+        let routes = routes
+            .into_iter()
+            .map(|(host, path)| (host.to_lowercase(), path))
+            .collect();
+        Self(Arc::new(routes))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Looks up the full authority first, then the bare host.
+    pub fn get(&self, uri: &Uri) -> Option<&PathBuf> {
+        let authority = uri.authority()?.as_str().to_lowercase();
+        let host = uri.host()?.to_lowercase();
+        self.0.get(&authority).or_else(|| self.0.get(&host))
+    }
+}
+
+/// Connector dialing the Unix socket routed for the request host.
+#[derive(Clone)]
+pub(crate) struct UnixConnector {
+    routes: UnixRoutes,
+}
+
+impl UnixConnector {
+    pub fn new(routes: UnixRoutes) -> Self {
+        Self { routes }
+    }
+}
+
+impl Service<Uri> for UnixConnector {
+    type Response = UnixConnection;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let path = self.routes.get(&uri).cloned();
+        Box::pin(async move {
+            let path = path.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no unix route for {uri}"))
+            })?;
+            Ok(UnixConnection(UnixStream::connect(path).await?))
+        })
+    }
+}
+
+#[pin_project]
+pub(crate) struct UnixConnection(#[pin] UnixStream);
+
+impl Connection for UnixConnection {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for UnixConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().0.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.project().0.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().0.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().0.poll_shutdown(cx)
+    }
+}
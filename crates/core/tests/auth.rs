@@ -0,0 +1,62 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::*;
+use good_mitm_core::{Listener, Proxy, ProxyAuth};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+mod common;
+
+/// Starts a proxy accepting `user` with password `secret`.
+async fn start_proxy() -> SocketAddr {
+    let addr = free_addr().await;
+    let mut auth = ProxyAuth::default();
+    auth.add_user("user".to_owned(), "secret".to_owned());
+    let proxy = Proxy::builder()
+        .listeners(vec![Listener::builder()
+            .addr(addr)
+            .auth(Some(auth))
+            .build()])
+        .shutdown_signal(std::future::pending())
+        .ca(ca())
+        .upstream_proxy(None)
+        .handler(Handler)
+        .build();
+    tokio::spawn(proxy.start_proxy());
+    wait_listening(addr).await;
+    addr
+}
+
+/// The response head of a CONNECT to `upstream` sending `credentials` as Basic auth.
+async fn connect(proxy: SocketAddr, upstream: SocketAddr, credentials: Option<&str>) -> String {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let auth = credentials
+        .map(|c| format!("Proxy-Authorization: Basic {}\r\n", STANDARD.encode(c)))
+        .unwrap_or_default();
+    let req = format!("CONNECT {upstream} HTTP/1.1\r\nHost: {upstream}\r\n{auth}\r\n");
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut res = vec![];
+    while !res.ends_with(b"\r\n\r\n") {
+        res.push(stream.read_u8().await.unwrap());
+    }
+    String::from_utf8(res).unwrap().to_lowercase()
+}
+
+#[tokio::test]
+async fn requires_credentials() {
+    let proxy = start_proxy().await;
+    let upstream = start_echo().await;
+
+    for credentials in [None, Some("user:wrong"), Some("other:secret")] {
+        let res = connect(proxy, upstream, credentials).await;
+        assert!(res.starts_with("http/1.1 407"), "{res}");
+        assert!(
+            res.contains("\r\nproxy-authenticate: basic realm=\"good-mitm\"\r\n"),
+            "{res}"
+        );
+    }
+    let res = connect(proxy, upstream, Some("user:secret")).await;
+    assert!(res.starts_with("http/1.1 200"), "{res}");
+}
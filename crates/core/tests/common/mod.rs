@@ -0,0 +1,68 @@
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use good_mitm_core::{
+    handler::{CustomContextData, HttpHandler},
+    CertificateAuthority,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
+
+#[derive(Clone, Default)]
+pub struct Ctx;
+
+impl CustomContextData for Ctx {}
+
+#[derive(Clone)]
+pub struct Handler;
+
+#[async_trait]
+impl HttpHandler<Ctx> for Handler {}
+
+pub fn ca() -> CertificateAuthority {
+    let cert = CertificateAuthority::gen_ca().unwrap();
+    CertificateAuthority::new(
+        rustls::PrivateKey(cert.serialize_private_key_der()),
+        rustls::Certificate(cert.serialize_der().unwrap()),
+        cert.serialize_pem().unwrap(),
+        100,
+    )
+    .unwrap()
+}
+
+pub async fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Waits until the proxy accepts connections on `addr`.
+pub async fn wait_listening(addr: SocketAddr) {
+    for _ in 0..50 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    panic!("proxy not listening on {addr}");
+}
+
+/// An upstream server echoing everything back, never closing first.
+pub async fn start_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
@@ -0,0 +1,220 @@
+use common::*;
+use good_mitm_core::{handler::MitmList, Event, Proxy, Timeouts};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+mod common;
+
+type Events = Arc<Mutex<Vec<Event>>>;
+
+/// Starts a proxy intercepting the hosts of `mitm`, recording its events.
+async fn start_proxy(timeouts: Timeouts, mitm: &[&str]) -> (SocketAddr, Events) {
+    let addr = free_addr().await;
+    let events = Events::default();
+    let recorded = events.clone();
+    let proxy = Proxy::builder()
+        .listen_addr(addr)
+        .shutdown_signal(std::future::pending())
+        .ca(ca())
+        .upstream_proxy(None)
+        .timeouts(timeouts)
+        .mitm_filters(MitmList::new(mitm.iter().map(|m| m.to_string()).collect()))
+        .event_listener(Arc::new(move |event: &Event| {
+            recorded.lock().unwrap().push(event.clone())
+        }))
+        .handler(Handler)
+        .build();
+    tokio::spawn(proxy.start_proxy());
+
+    wait_listening(addr).await;
+    (addr, events)
+}
+
+async fn connect(proxy: SocketAddr, upstream: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let req = format!("CONNECT {upstream} HTTP/1.1\r\nHost: {upstream}\r\n\r\n");
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut res = vec![];
+    while !res.ends_with(b"\r\n\r\n") {
+        res.push(stream.read_u8().await.unwrap());
+    }
+    assert!(res.starts_with(b"HTTP/1.1 200"), "{res:?}");
+    stream
+}
+
+async fn echo(stream: &mut TcpStream, data: &[u8]) {
+    stream.write_all(data).await.unwrap();
+    let mut echoed = vec![0; data.len()];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, data);
+}
+
+/// The first event matching `f`, waiting for it.
+async fn wait_for(events: &Events, f: impl Fn(&Event) -> bool) -> Event {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let event = events
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|event| f(event))
+                .cloned();
+            match event {
+                Some(event) => return event,
+                None => sleep(Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .expect("event not emitted")
+}
+
+async fn tunnel_close(events: &Events) -> Event {
+    wait_for(events, |event| matches!(event, Event::TunnelClose { .. })).await
+}
+
+/// The `intercept` of every MITM decision.
+fn mitm_decisions(events: &Events) -> Vec<bool> {
+    events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|event| match event {
+            Event::MitmDecision { intercept, .. } => Some(*intercept),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn tunnel_events() {
+    let (proxy, events) = start_proxy(Timeouts::default(), &[]).await;
+    let upstream = start_echo().await;
+
+    let mut stream = connect(proxy, upstream).await;
+    echo(&mut stream, b"hello").await;
+    echo(&mut stream, b"world!").await;
+    stream.shutdown().await.unwrap();
+    // the echo server closes once the client did
+    assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+
+    let Event::TunnelClose {
+        host,
+        sent,
+        received,
+        error,
+        ..
+    } = tunnel_close(&events).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(host, upstream.to_string());
+    assert_eq!((sent, received), (11, 11));
+    assert_eq!(error, None);
+
+    assert_eq!(mitm_decisions(&events), [false]);
+    assert!(events.lock().unwrap().iter().any(|event| matches!(
+        event,
+        Event::Connect { authority, .. } if *authority == upstream.to_string()
+    )));
+}
+
+#[tokio::test]
+async fn intercepted_connect_decides_once() {
+    let (proxy, events) = start_proxy(Timeouts::default(), &["*"]).await;
+    let upstream = start_echo().await;
+
+    let mut stream = connect(proxy, upstream).await;
+    stream.write_all(&client_hello(false)).await.unwrap();
+    // the minimal ClientHello offers nothing rustls accepts
+    wait_for(&events, |event| {
+        matches!(event, Event::TlsHandshakeFailed { .. })
+    })
+    .await;
+    assert_eq!(mitm_decisions(&events), [true]);
+}
+
+#[tokio::test]
+async fn tunnel_timeout_keeps_byte_counts() {
+    let timeouts = Timeouts {
+        tunnel: Some(Duration::from_millis(300)),
+        ..Default::default()
+    };
+    let (proxy, events) = start_proxy(timeouts, &[]).await;
+    let upstream = start_echo().await;
+
+    let mut stream = connect(proxy, upstream).await;
+    echo(&mut stream, b"hello").await;
+
+    let Event::TunnelClose {
+        sent,
+        received,
+        error,
+        ..
+    } = tunnel_close(&events).await
+    else {
+        unreachable!()
+    };
+    assert_eq!((sent, received), (5, 5));
+    assert!(error.is_some());
+}
+
+/// A TLS record with a ClientHello for `localhost`, offering Encrypted Client Hello if `ech`.
+fn client_hello(ech: bool) -> Vec<u8> {
+    fn with_len(len_bytes: usize, data: &[u8]) -> Vec<u8> {
+        let len = (data.len() as u32).to_be_bytes();
+        [&len[4 - len_bytes..], data].concat()
+    }
+
+    let server_name = with_len(2, &[&[0], &with_len(2, b"localhost")[..]].concat());
+    // outer ECH: cipher suite, config id, enc and payload
+    let ech_data = [
+        &[0, 0, 1, 0, 1, 7][..],
+        &with_len(2, &[1]),
+        &with_len(2, &[2]),
+    ]
+    .concat();
+    let mut extensions = [&[0, 0][..], &with_len(2, &server_name)].concat();
+    if ech {
+        extensions.extend([0xfe, 0x0d]);
+        extensions.extend(with_len(2, &ech_data));
+    }
+    let hello = [
+        &[3, 3][..],
+        &[0; 32],
+        &[0],
+        &with_len(2, &[0x13, 0x01]),
+        &with_len(1, &[0]),
+        &with_len(2, &extensions),
+    ]
+    .concat();
+    let handshake = [&[1][..], &with_len(3, &hello)].concat();
+    [&[0x16, 3, 1][..], &with_len(2, &handshake)].concat()
+}
+
+#[tokio::test]
+async fn redirected_ech_is_tunneled() {
+    let (proxy, events) = start_proxy(Timeouts::default(), &["*"]).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&client_hello(true)).await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    let Event::TunnelClose { host, .. } = tunnel_close(&events).await else {
+        unreachable!()
+    };
+    assert_eq!(host, "localhost:443");
+    assert_eq!(mitm_decisions(&events), [false]);
+    assert!(!events.lock().unwrap().iter().any(|event| matches!(
+        event,
+        Event::TlsHandshake { .. } | Event::TlsHandshakeFailed { .. }
+    )));
+}
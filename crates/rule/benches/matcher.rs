@@ -0,0 +1,113 @@
+use cached::{proc_macro::cached, SizedCache};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use good_mitm_rule::{Action, Filter, Regex, Rule, RuleHandlerCtx, RuleMatcher};
+use hyper::{Body, Request};
+use mitm_core::mitm::HttpContext;
+
+/// Regexes as compiled per request before rules were precompiled.
+#[cached(
+    type = "SizedCache<String, fancy_regex::Regex>",
+    create = "{ SizedCache::with_size(100) }",
+    convert = r#"{ re.to_string() }"#
+)]
+fn get_regex(re: &str) -> fancy_regex::Regex {
+    fancy_regex::Regex::new(re).unwrap()
+}
+
+/// Matching before rules were precompiled: every filter of every rule, lowercasing the host
+/// and looking up regexes by their pattern on each call.
+fn is_match_req(filter: &Filter, req: &Request<Body>) -> bool {
+    let host = req.uri().host().unwrap_or_default().to_lowercase();
+    match filter {
+        Filter::Domain(target) => host == *target,
+        Filter::DomainKeyword(target) => host.contains(target),
+        Filter::DomainPrefix(target) => host.starts_with(target),
+        Filter::DomainSuffix(target) => host.ends_with(target),
+        Filter::UrlRegex(target) => {
+            let url = req.uri().to_string();
+            get_regex(target.as_str()).is_match(&url).unwrap()
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn rules(n: usize) -> Vec<Rule> {
+    (0..n)
+        .map(|i| {
+            let filter = match i % 5 {
+                0 => Filter::Domain(format!("www.site{i}.com")),
+                1 => Filter::DomainSuffix(format!(".site{i}.net")),
+                2 => Filter::DomainPrefix(format!("ads{i}.")),
+                3 => Filter::DomainKeyword(format!("tracker{i}")),
+                _ => {
+                    let re = format!(r"^https?://[\w.]+/api/v{i}/(ads|stats)");
+                    Filter::UrlRegex(Regex::new(&re).unwrap())
+                }
+            };
+            Rule {
+                filters: vec![filter],
+                response_filters: vec![],
+                actions: vec![Action::LogReq],
+                priority: 0,
+                stop: false,
+                url: None,
+            }
+        })
+        .collect()
+}
+
+fn requests(n: usize) -> Vec<Request<Body>> {
+    [
+        format!("https://www.site{}.com/", n / 2 / 5 * 5),
+        format!("https://cdn.site{}.net/a.js", n / 3 / 5 * 5 + 1),
+        format!("https://tracker{}.example.org/p", n / 5 * 5 + 3),
+        format!("https://api.example.com/api/v{}/ads", n / 4 / 5 * 5 + 4),
+        "https://nomatch.example.com/index.html".to_owned(),
+    ]
+    .iter()
+    .map(|uri| Request::get(uri.as_str()).body(Body::empty()).unwrap())
+    .collect()
+}
+
+fn linear(rules: &[Rule], req: &Request<Body>) -> Vec<usize> {
+    rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| rule.filters.iter().any(|f| is_match_req(f, req)))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn bench_match(c: &mut Criterion) {
+    let ctx = HttpContext::<RuleHandlerCtx>::default();
+    let mut group = c.benchmark_group("match");
+    // linear matching of large rule sets takes seconds
+    group.sample_size(10);
+    for n in [100, 1_000, 10_000] {
+        let rules = rules(n);
+        let matcher = RuleMatcher::new(&rules);
+        let requests = requests(n);
+        for req in &requests {
+            assert_eq!(linear(&rules, req), matcher.matches(&ctx, req));
+        }
+
+        group.bench_with_input(BenchmarkId::new("linear", n), &requests, |b, requests| {
+            b.iter(|| {
+                for req in requests {
+                    linear(&rules, req);
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("matcher", n), &requests, |b, requests| {
+            b.iter(|| {
+                for req in requests {
+                    matcher.matches(&ctx, req);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_match);
+criterion_main!(benches);
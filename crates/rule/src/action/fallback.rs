@@ -0,0 +1,68 @@
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Body, HeaderMap, Response, StatusCode,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+/// Response served instead of an error page when the upstream request fails.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Fallback {
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    /// Serve the content of this file as body, e.g. a saved copy of the page.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Fallback {
+    pub async fn response(&self) -> Option<Response<Body>> {
+        let body = match (&self.file, &self.body) {
+            (Some(file), _) => match tokio::fs::read(file).await {
+                Ok(content) => Body::from(content),
+                Err(err) => {
+                    error!("fallback file {}: {}", file.display(), err);
+                    return None;
+                }
+            },
+            (None, Some(body)) => Body::from(body.clone()),
+            (None, None) => Body::empty(),
+        };
+
+        let mut headers = HeaderMap::new();
+        for (key, value) in &self.headers {
+            match (HeaderName::try_from(key), value.parse()) {
+                (Ok(key), Ok(value)) => {
+                    headers.insert(key, value);
+                }
+                _ => error!("fallback header invalid: {}: {}", key, value),
+            }
+        }
+
+        let mut res = Response::new(body);
+        *res.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        *res.headers_mut() = headers;
+        Some(res)
+    }
+
+    pub(crate) fn validate(&self, problems: &mut Vec<String>) {
+        if StatusCode::from_u16(self.status).is_err() {
+            problems.push(format!("invalid fallback status {}", self.status));
+        }
+        for (key, value) in &self.headers {
+            if HeaderName::try_from(key).is_err() || HeaderValue::from_str(value).is_err() {
+                problems.push(format!("invalid fallback header {key:?}: {value:?}"));
+            }
+        }
+    }
+}
@@ -1,9 +1,12 @@
+mod fallback;
 #[cfg(feature = "js")]
 pub mod js;
 mod log;
 mod modify;
 
 pub use self::log::*;
+pub use fallback::Fallback;
+use hyper::{header::HeaderValue, Uri};
 pub use modify::Modify;
 use serde::{Deserialize, Serialize};
 
@@ -16,7 +19,28 @@ pub enum Action {
     ModifyResponse(Modify),
     LogRes,
     LogReq,
-
-    #[cfg(feature = "js")]
+    Fallback(Fallback),
+    /// Rejected by validation without the `js` feature.
     Js(String),
 }
+
+impl Action {
+    /// Problems the deserializer can't catch, e.g. an invalid header value.
+    pub(crate) fn validate(&self, problems: &mut Vec<String>) {
+        match self {
+            Self::Redirect(target) => {
+                if HeaderValue::from_str(target).is_err() {
+                    problems.push(format!("invalid redirect target {target:?}"));
+                } else if !target.contains('$') && target.parse::<Uri>().is_err() {
+                    problems.push(format!("invalid redirect url {target:?}"));
+                }
+            }
+            Self::ModifyRequest(modify) => modify.validate(false, problems),
+            Self::ModifyResponse(modify) => modify.validate(true, problems),
+            Self::Fallback(fallback) => fallback.validate(problems),
+            #[cfg(not(feature = "js"))]
+            Self::Js(_) => problems.push("the js action requires the js feature".to_owned()),
+            _ => {}
+        }
+    }
+}
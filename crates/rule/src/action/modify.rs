@@ -2,23 +2,52 @@ use cookie::{Cookie, CookieJar};
 use http::{header::HeaderName, HeaderValue, Uri};
 use hyper::{body::*, header, Body, HeaderMap, Request, Response, StatusCode};
 use log::error;
-use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{fmt, str::FromStr};
 
-use crate::cache::get_regex;
+use crate::re::Regex;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum TextModify {
     Set(String),
     Complex(TextModifyComplex),
 }
 
+// Not untagged, that would hide why a complex modify is invalid.
+impl<'de> Deserialize<'de> for TextModify {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TextModifyVisitor;
+
+        impl<'de> Visitor<'de> for TextModifyVisitor {
+            type Value = TextModify;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or a map")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(TextModify::Set(v.to_owned()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                TextModifyComplex::deserialize(MapAccessDeserializer::new(map))
+                    .map(TextModify::Complex)
+            }
+        }
+
+        deserializer.deserialize_any(TextModifyVisitor)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct TextModifyComplex {
     pub origin: Option<String>,
-    pub re: Option<String>,
+    pub re: Option<Regex>,
     pub new: String,
 }
 
@@ -32,7 +61,7 @@ impl TextModify {
                 }
 
                 if let Some(ref re) = md.re {
-                    return get_regex(re).replace_all(text, &md.new).to_string();
+                    return re.replace_all(text, &md.new).to_string();
                 }
 
                 md.new.clone()
@@ -51,6 +80,20 @@ pub struct MapModify {
     pub remove: bool,
 }
 
+impl MapModify {
+    /// Only the replacement, the value it's applied to isn't known yet.
+    fn validate_value(&self, problems: &mut Vec<String>) {
+        let new = match &self.value {
+            Some(TextModify::Set(new)) => new,
+            Some(TextModify::Complex(md)) => &md.new,
+            None => return,
+        };
+        if HeaderValue::from_str(new).is_err() {
+            problems.push(format!("invalid value {new:?} of {}", self.key));
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Modify {
@@ -106,7 +149,7 @@ impl Modify {
                 let mut cookies_jar = CookieJar::new();
 
                 if let Some(cookies) = req.headers().get(header::COOKIE) {
-                    let cookies = cookies.to_str().unwrap().to_string();
+                    let cookies = String::from_utf8_lossy(cookies.as_bytes()).to_string();
                     let cookies: Vec<String> = cookies.split("; ").map(String::from).collect();
                     for c in cookies {
                         if let Ok(c) = Cookie::parse(c) {
@@ -134,8 +177,12 @@ impl Modify {
 
                 let cookies: Vec<String> = cookies_jar.iter().map(|c| c.to_string()).collect();
                 let cookies = cookies.join("; ");
-                req.headers_mut()
-                    .insert(header::COOKIE, HeaderValue::from_str(&cookies).unwrap());
+                match HeaderValue::from_str(&cookies) {
+                    Ok(cookies) => {
+                        req.headers_mut().insert(header::COOKIE, cookies);
+                    }
+                    Err(err) => error!("cookie modify error: {}", err),
+                }
 
                 Some(req)
             }
@@ -222,14 +269,22 @@ impl Modify {
                 let cookies: Vec<String> = cookies_jar.iter().map(|c| c.to_string()).collect();
                 let cookies = cookies.join("; ");
                 let header = res.headers_mut();
-                header.insert(header::COOKIE, HeaderValue::from_str(&cookies).unwrap());
+                match HeaderValue::from_str(&cookies) {
+                    Ok(cookies) => {
+                        header.insert(header::COOKIE, cookies);
+                    }
+                    Err(err) => error!("cookie modify error: {}", err),
+                }
 
                 header.remove(header::SET_COOKIE);
                 for sc in set_cookies_jar.iter() {
-                    header.append(
-                        header::SET_COOKIE,
-                        HeaderValue::from_str(&sc.to_string()).unwrap(),
-                    );
+                    match HeaderValue::from_str(&sc.to_string()) {
+                        Ok(sc) => header.append(header::SET_COOKIE, sc),
+                        Err(err) => {
+                            error!("cookie modify error: {}", err);
+                            continue;
+                        }
+                    };
                 }
 
                 res
@@ -241,19 +296,51 @@ impl Modify {
         }
     }
 
+    pub(crate) fn validate(&self, response: bool, problems: &mut Vec<String>) {
+        match self {
+            Modify::Url(_) if response => problems.push("modify response url not supported".into()),
+            Modify::Url(TextModify::Set(url)) => {
+                if Uri::from_str(url).is_err() {
+                    problems.push(format!("invalid url {url:?}"));
+                }
+            }
+            Modify::Header(md) => {
+                if HeaderName::from_str(&md.key).is_err() {
+                    problems.push(format!("invalid header name {:?}", md.key));
+                }
+                md.validate_value(problems);
+            }
+            Modify::Cookie(md) => {
+                if md.key.is_empty() || HeaderValue::from_str(&md.key).is_err() {
+                    problems.push(format!("invalid cookie name {:?}", md.key));
+                }
+                md.validate_value(problems);
+            }
+            Modify::Url(_) | Modify::Body(_) => {}
+        }
+    }
+
     fn modify_header(&self, header: &mut HeaderMap, md: &MapModify) {
         if md.remove {
             header.remove(&md.key);
         } else if let Some(ref text_md) = md.value {
             if let Some(h) = header.get_mut(&md.key) {
                 let new_header_value = text_md.exec_action(h.to_str().unwrap_or_default());
-                *h = header::HeaderValue::from_str(new_header_value.as_str()).unwrap();
+                match HeaderValue::from_str(new_header_value.as_str()) {
+                    Ok(value) => *h = value,
+                    Err(err) => error!("header modify error: {}", err),
+                }
             } else {
                 let new_header_value = text_md.exec_action("");
-                header.append(
-                    HeaderName::from_str(&md.key).unwrap(),
-                    header::HeaderValue::from_str(new_header_value.as_str()).unwrap(),
-                );
+                match (
+                    HeaderName::from_str(&md.key),
+                    HeaderValue::from_str(new_header_value.as_str()),
+                ) {
+                    (Ok(key), Ok(value)) => {
+                        header.append(key, value);
+                    }
+                    _ => error!("header modify error: {}: {}", md.key, new_header_value),
+                }
             }
         }
     }
@@ -1,11 +1,24 @@
-use cached::{proc_macro::cached, SizedCache};
-use fancy_regex::Regex;
+use crate::set::{DomainSet, FileSet, UrlRegexSet};
+use cached::{proc_macro::cached, UnboundCache};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 #[cached(
-    type = "SizedCache<String, Regex>",
-    create = "{ SizedCache::with_size(100) }",
-    convert = r#"{ re.to_string() }"#
+    type = "UnboundCache<PathBuf, Arc<FileSet<DomainSet>>>",
+    create = "{ UnboundCache::new() }",
+    convert = r#"{ path.to_owned() }"#
 )]
-pub fn get_regex(re: &str) -> Regex {
-    fancy_regex::Regex::new(re).unwrap()
+pub fn get_domain_set(path: &Path) -> Arc<FileSet<DomainSet>> {
+    FileSet::new(path)
+}
+
+#[cached(
+    type = "UnboundCache<PathBuf, Arc<FileSet<UrlRegexSet>>>",
+    create = "{ UnboundCache::new() }",
+    convert = r#"{ path.to_owned() }"#
+)]
+pub fn get_url_regex_set(path: &Path) -> Arc<FileSet<UrlRegexSet>> {
+    FileSet::new(path)
 }
@@ -1,7 +1,23 @@
-use hyper::{Body, Request};
+use bytes::Bytes;
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderName},
+    http::{uri::Scheme, Extensions},
+    Body, Method, Request, Response,
+};
+use ipnet::IpNet;
+use mitm_core::{handler::CustomContextData, mitm::HttpContext};
 use serde::{Deserialize, Serialize};
+use serde_json_path::JsonPath;
+use std::{net::IpAddr, ops::RangeInclusive, path::PathBuf};
 
-use crate::cache::get_regex;
+use crate::{
+    cache::{get_domain_set, get_url_regex_set},
+    glob::Glob,
+    mitm::MitmHost,
+    re::Regex,
+    set::{validate_file, DomainSet, UrlRegexSet},
+};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -11,7 +27,36 @@ pub enum Filter {
     DomainKeyword(String),
     DomainPrefix(String),
     DomainSuffix(String),
-    UrlRegex(String),
+    UrlRegex(Regex),
+    /// A file of domains, `.example.com` including subdomains, `keyword:example`.
+    DomainSet(PathBuf),
+    /// A file of URL regular expressions, one per line.
+    UrlRegexSet(PathBuf),
+    #[serde(with = "ip_net")]
+    ClientIp(IpNet),
+    /// The IP of the host, or the addresses it resolves to.
+    #[serde(with = "ip_net")]
+    IpCidr(IpNet),
+    Ja3(String),
+    Ja4(String),
+    Method(String),
+    Path(String),
+    PathPrefix(String),
+    /// Wildcard pattern of the path, `*` and `?`.
+    PathGlob(Glob),
+    /// `key` for presence or `key=value`.
+    Query(String),
+    Scheme(String),
+    /// The destination port, the default of the scheme if not given.
+    Port(u16),
+    Header(MapFilter),
+    Cookie(MapFilter),
+    Body(BodyFilter),
+    /// Matches when every filter matches.
+    AllOf(Vec<Filter>),
+    /// Matches when any filter matches.
+    AnyOf(Vec<Filter>),
+    Not(Box<Filter>),
 }
 
 impl Filter {
@@ -22,13 +67,53 @@ impl Filter {
             Filter::DomainKeyword(d) => Self::DomainKeyword(d.to_lowercase()),
             Filter::DomainPrefix(d) => Self::DomainPrefix(d.to_lowercase()),
             Filter::DomainSuffix(d) => Self::DomainSuffix(d.to_lowercase()),
-            Filter::UrlRegex(re) => Self::UrlRegex(re.to_owned()),
+            Filter::UrlRegex(re) => Self::UrlRegex(re.clone()),
+            // load now, not on the first request
+            Filter::DomainSet(path) => {
+                get_domain_set(path);
+                self.to_owned()
+            }
+            Filter::UrlRegexSet(path) => {
+                get_url_regex_set(path);
+                self.to_owned()
+            }
+            Filter::ClientIp(net) => Self::ClientIp(net.trunc()),
+            Filter::IpCidr(net) => Self::IpCidr(net.trunc()),
+            Filter::Ja3(ja3) => Self::Ja3(ja3.to_lowercase()),
+            Filter::Ja4(ja4) => Self::Ja4(ja4.to_lowercase()),
+            Filter::Method(method) => Self::Method(method.to_uppercase()),
+            Filter::Header(map) => Self::Header(map.init()),
+            Filter::Cookie(map) => Self::Cookie(map.init()),
+            Filter::Body(_) => self.to_owned(),
+            Filter::Scheme(scheme) => Self::Scheme(scheme.to_lowercase()),
+            Filter::Path(_)
+            | Filter::PathPrefix(_)
+            | Filter::PathGlob(_)
+            | Filter::Query(_)
+            | Filter::Port(_) => self.to_owned(),
+            Filter::AllOf(filters) => Self::AllOf(filters.iter().map(Self::init).collect()),
+            Filter::AnyOf(filters) => Self::AnyOf(filters.iter().map(Self::init).collect()),
+            Filter::Not(filter) => Self::Not(Box::new(filter.init())),
         }
     }
 
-    pub fn is_match_req(&self, req: &Request<Body>) -> bool {
+    pub fn is_match_req<D: CustomContextData>(
+        &self,
+        ctx: &HttpContext<D>,
+        req: &Request<Body>,
+    ) -> bool {
+        self.eval_req(ctx, req).unwrap_or_default()
+    }
+
+    /// Like [`is_match_req`](Self::is_match_req), `None` if the result depends on a body
+    /// that isn't buffered yet.
+    pub(crate) fn eval_req<D: CustomContextData>(
+        &self,
+        ctx: &HttpContext<D>,
+        req: &Request<Body>,
+    ) -> Option<bool> {
         let host = req.uri().host().unwrap_or_default().to_lowercase();
-        match self {
+        let matched = match self {
             Self::All => true,
             Self::Domain(target) => host == *target,
             Self::DomainKeyword(target) => host.contains(target),
@@ -36,19 +121,578 @@ impl Filter {
             Self::DomainSuffix(target) => host.ends_with(target),
             Self::UrlRegex(target) => {
                 let url = req.uri().to_string();
-                get_regex(target).is_match(&url).unwrap()
+                target.is_match(&url)
+            }
+            Self::DomainSet(path) => get_domain_set(path).get().contains(&host),
+            Self::UrlRegexSet(path) => get_url_regex_set(path)
+                .get()
+                .is_match(&req.uri().to_string()),
+            Self::ClientIp(net) => ctx
+                .client_addr
+                .map(|addr| net.contains(&addr.ip().to_canonical()))
+                .unwrap_or_default(),
+            Self::Ja3(target) => ctx
+                .client_hello
+                .as_ref()
+                .map(|hello| hello.ja3 == *target || hello.ja3_string() == *target)
+                .unwrap_or_default(),
+            // the first part alone matches TLS version, counts and ALPN
+            Self::Ja4(target) => ctx
+                .client_hello
+                .as_ref()
+                .map(|hello| {
+                    hello.ja4 == *target || hello.ja4.split('_').next() == Some(target.as_str())
+                })
+                .unwrap_or_default(),
+            Self::Method(method) => req.method().as_str() == method,
+            Self::Path(path) => req.uri().path() == path,
+            Self::PathPrefix(prefix) => req.uri().path().starts_with(prefix.as_str()),
+            Self::PathGlob(glob) => glob.matches(req.uri().path()),
+            Self::Query(target) => {
+                let (key, value) = match target.split_once('=') {
+                    Some((key, value)) => (key, Some(value)),
+                    None => (target.as_str(), None),
+                };
+                req.uri()
+                    .query()
+                    .unwrap_or_default()
+                    .split('&')
+                    .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+                    .any(|(k, v)| k == key && value.is_none_or(|value| v == value))
+            }
+            Self::Scheme(scheme) => req.uri().scheme_str() == Some(scheme.as_str()),
+            Self::Port(port) => {
+                let default = match req.uri().scheme() {
+                    Some(scheme) if *scheme == Scheme::HTTPS => 443,
+                    _ => 80,
+                };
+                req.uri().port_u16().unwrap_or(default) == *port
+            }
+            Self::Header(map) => req
+                .headers()
+                .get_all(map.key.as_str())
+                .iter()
+                .any(|value| map.is_match(&String::from_utf8_lossy(value.as_bytes()))),
+            Self::Cookie(map) => req
+                .headers()
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|cookies| cookies.to_str().ok())
+                .flat_map(|cookies| cookies.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .any(|(key, value)| key.eq_ignore_ascii_case(&map.key) && map.is_match(value)),
+            Self::IpCidr(net) => {
+                let ip = host.trim_matches(&['[', ']'][..]).parse::<IpAddr>();
+                match (ip, req.extensions().get::<ResolvedAddrs>()) {
+                    (Ok(ip), _) => net.contains(&ip.to_canonical()),
+                    (_, Some(ResolvedAddrs(addrs))) => addrs.iter().any(|ip| net.contains(ip)),
+                    (_, None) => return None,
+                }
+            }
+            Self::Body(body) => return body.eval(req.extensions()),
+            Self::AllOf(filters) => return all_of(filters.iter().map(|f| f.eval_req(ctx, req))),
+            Self::AnyOf(filters) => return any_of(filters.iter().map(|f| f.eval_req(ctx, req))),
+            Self::Not(filter) => return filter.eval_req(ctx, req).map(|matched| !matched),
+        };
+        Some(matched)
+    }
+
+    /// Wildcard patterns of the hosts this filter can match, empty when it doesn't depend on
+    /// the host and `mitm` has to be specified.
+    pub fn mitm_filtter_pattern(&self) -> Vec<String> {
+        self.mitm_hosts()
+            .into_iter()
+            .flat_map(|host| match host {
+                MitmHost::Pattern(pattern) => vec![pattern],
+                MitmHost::DomainSet(path) => get_domain_set(&path).get().mitm_patterns(),
+            })
+            .collect()
+    }
+
+    /// Hosts to intercept for this filter, empty if it doesn't restrict the host.
+    pub fn mitm_hosts(&self) -> Vec<MitmHost> {
+        let pattern = |p: String| vec![MitmHost::Pattern(p)];
+        let any = || pattern("*".to_owned());
+        let is_any = |host: &MitmHost| matches!(host, MitmHost::Pattern(p) if p == "*");
+        match self {
+            Self::All => any(),
+            Self::Domain(d) => pattern(d.to_owned()),
+            Self::DomainKeyword(d) => pattern(format!("*{}*", d)),
+            Self::DomainPrefix(d) => pattern(format!("{}*", d)),
+            Self::DomainSuffix(d) => pattern(format!("*{}", d)),
+            Self::IpCidr(net) => pattern(net.to_string()),
+            Self::DomainSet(path) => vec![MitmHost::DomainSet(path.to_owned())],
+            // hosts matching every filter, so any restricting one will do, `not` never
+            // restricts beyond `*`
+            Self::AllOf(filters) => {
+                let hosts: Vec<_> = filters
+                    .iter()
+                    .map(Self::mitm_hosts)
+                    .filter(|h| !h.is_empty())
+                    .collect();
+                match hosts.iter().find(|h| !h.iter().any(is_any)) {
+                    Some(restricting) => restricting.to_owned(),
+                    None if hosts.is_empty() => vec![],
+                    None => any(),
+                }
+            }
+            // hosts matching any filter, a filter not restricting the host needs `mitm`
+            Self::AnyOf(filters) => {
+                let hosts: Vec<_> = filters.iter().map(Self::mitm_hosts).collect();
+                if hosts.iter().any(Vec::is_empty) {
+                    vec![]
+                } else if hosts.iter().flatten().any(is_any) {
+                    any()
+                } else {
+                    hosts.concat()
+                }
             }
+            Self::Not(filter) => match filter.as_ref() {
+                Self::All => vec![],
+                Self::Not(filter) => filter.mitm_hosts(),
+                // any other host may match
+                filter if !filter.mitm_hosts().is_empty() => any(),
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Problems the deserializer can't catch, e.g. an invalid header name.
+    pub(crate) fn validate(&self, problems: &mut Vec<String>) {
+        match self {
+            Self::Method(method) if Method::from_bytes(method.as_bytes()).is_err() => {
+                problems.push(format!("invalid method {method:?}"))
+            }
+            Self::Header(map) => map.validate_header(problems),
+            Self::DomainSet(path) => validate_file::<DomainSet>(path, problems),
+            Self::UrlRegexSet(path) => validate_file::<UrlRegexSet>(path, problems),
+            Self::AllOf(filters) | Self::AnyOf(filters) => {
+                filters.iter().for_each(|f| f.validate(problems))
+            }
+            Self::Not(filter) => filter.validate(problems),
+            _ => {}
+        }
+    }
+
+    /// Whether this filter or any nested one satisfies `f`.
+    pub(crate) fn any(&self, f: &impl Fn(&Filter) -> bool) -> bool {
+        f(self)
+            || match self {
+                Self::AllOf(filters) | Self::AnyOf(filters) => filters.iter().any(|i| i.any(f)),
+                Self::Not(filter) => filter.any(f),
+                _ => false,
+            }
+    }
+}
+
+/// Conditions on the response, for the response actions of a rule.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResponseFilter {
+    /// A status code like `200`, a class like `2xx` or a range like `200-299`.
+    #[serde(with = "status_range")]
+    Status(RangeInclusive<u16>),
+    /// Contained in the `Content-Type`, e.g. `json` or `text/html`.
+    ContentType(String),
+    Header(MapFilter),
+    /// Size of the body by `Content-Length`, unknown sizes never match.
+    BodySize(SizeRange),
+    Body(BodyFilter),
+    AllOf(Vec<ResponseFilter>),
+    AnyOf(Vec<ResponseFilter>),
+    Not(Box<ResponseFilter>),
+}
+
+impl ResponseFilter {
+    pub fn init(&self) -> Self {
+        match self {
+            Self::ContentType(content_type) => Self::ContentType(content_type.to_lowercase()),
+            Self::Header(map) => Self::Header(map.init()),
+            Self::AllOf(filters) => Self::AllOf(filters.iter().map(Self::init).collect()),
+            Self::AnyOf(filters) => Self::AnyOf(filters.iter().map(Self::init).collect()),
+            Self::Not(filter) => Self::Not(Box::new(filter.init())),
+            Self::Status(_) | Self::BodySize(_) | Self::Body(_) => self.to_owned(),
         }
     }
 
-    pub fn mitm_filtter_pattern(&self) -> Option<String> {
+    pub(crate) fn validate(&self, problems: &mut Vec<String>) {
         match self {
-            Self::All => Some("*".to_owned()),
-            Self::Domain(d) => Some(d.to_owned()),
-            Self::DomainKeyword(d) => Some(format!("*{}*", d)),
-            Self::DomainPrefix(d) => Some(format!("{}*", d)),
-            Self::DomainSuffix(d) => Some(format!("*{}", d)),
-            _ => None,
+            Self::Status(range) => {
+                if range.is_empty() || *range.start() < 100 || *range.end() > 599 {
+                    problems.push(format!(
+                        "invalid status range {}-{}",
+                        range.start(),
+                        range.end()
+                    ));
+                }
+            }
+            Self::Header(map) => map.validate_header(problems),
+            Self::AllOf(filters) | Self::AnyOf(filters) => {
+                filters.iter().for_each(|f| f.validate(problems))
+            }
+            Self::Not(filter) => filter.validate(problems),
+            Self::ContentType(_) | Self::BodySize(_) | Self::Body(_) => {}
+        }
+    }
+
+    pub fn is_match_res(&self, res: &Response<Body>) -> bool {
+        self.eval_res(res).unwrap_or_default()
+    }
+
+    /// Like [`is_match_res`](Self::is_match_res), `None` if the result depends on a body
+    /// that isn't buffered yet.
+    pub(crate) fn eval_res(&self, res: &Response<Body>) -> Option<bool> {
+        let matched = match self {
+            Self::Status(range) => range.contains(&res.status().as_u16()),
+            Self::ContentType(target) => res
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(|content_type| content_type.to_lowercase().contains(target)),
+            Self::Header(map) => res
+                .headers()
+                .get_all(map.key.as_str())
+                .iter()
+                .any(|value| map.is_match(&String::from_utf8_lossy(value.as_bytes()))),
+            Self::BodySize(range) => {
+                let content_length = res
+                    .headers()
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|len| len.to_str().ok()?.parse().ok());
+                content_length
+                    .or_else(|| res.body().size_hint().exact())
+                    .is_some_and(|size| range.contains(size))
+            }
+            Self::Body(body) => return body.eval(res.extensions()),
+            Self::AllOf(filters) => return all_of(filters.iter().map(|f| f.eval_res(res))),
+            Self::AnyOf(filters) => return any_of(filters.iter().map(|f| f.eval_res(res))),
+            Self::Not(filter) => return filter.eval_res(res).map(|matched| !matched),
+        };
+        Some(matched)
+    }
+}
+
+/// Matches the body, every condition given must hold.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BodyFilter {
+    #[serde(default)]
+    pub contains: Option<String>,
+    #[serde(default)]
+    pub regex: Option<Regex>,
+    /// A JSONPath like `$.operationName` that must exist, equal to `value` if given.
+    #[serde(default)]
+    pub json_path: Option<JsonPath>,
+    #[serde(default)]
+    pub value: Option<serde_json::Value>,
+}
+
+impl BodyFilter {
+    fn eval(&self, extensions: &Extensions) -> Option<bool> {
+        let BufferedBody(body) = extensions.get::<BufferedBody>()?;
+        Some(self.is_match(body))
+    }
+
+    fn is_match(&self, body: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(body);
+        if let Some(contains) = &self.contains {
+            if !text.contains(contains.as_str()) {
+                return false;
+            }
+        }
+        if let Some(re) = &self.regex {
+            if !re.is_match(&text) {
+                return false;
+            }
+        }
+        if let Some(path) = &self.json_path {
+            let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) else {
+                return false;
+            };
+            let nodes = path.query(&json);
+            return match &self.value {
+                Some(value) => nodes.iter().any(|node| *node == value),
+                None => !nodes.is_empty(),
+            };
+        }
+        true
+    }
+}
+
+/// The addresses the host of a request resolves to, kept in its extensions.
+#[derive(Debug, Clone)]
+pub(crate) struct ResolvedAddrs(pub Vec<IpAddr>);
+
+/// The body of a request or response read for [`BodyFilter`]s, kept in its extensions.
+#[derive(Debug, Clone)]
+pub(crate) struct BufferedBody(pub Bytes);
+
+/// Combines results that may be unknown, `None`, like `all`.
+pub(crate) fn all_of(results: impl Iterator<Item = Option<bool>>) -> Option<bool> {
+    let mut all = Some(true);
+    for result in results {
+        match result {
+            Some(false) => return Some(false),
+            None => all = None,
+            Some(true) => {}
         }
     }
+    all
+}
+
+/// Combines results that may be unknown, `None`, like `any`.
+pub(crate) fn any_of(results: impl Iterator<Item = Option<bool>>) -> Option<bool> {
+    let mut any = Some(false);
+    for result in results {
+        match result {
+            Some(true) => return Some(true),
+            None => any = None,
+            Some(false) => {}
+        }
+    }
+    any
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SizeRange {
+    #[serde(default)]
+    pub min: Option<u64>,
+    #[serde(default)]
+    pub max: Option<u64>,
+}
+
+impl SizeRange {
+    fn contains(&self, size: u64) -> bool {
+        self.min.is_none_or(|min| size >= min) && self.max.is_none_or(|max| size <= max)
+    }
+}
+
+/// Matches a header or cookie by its case-insensitive name, present with any value if
+/// neither `value` nor `regex` is given.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MapFilter {
+    pub key: String,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub regex: Option<Regex>,
+}
+
+impl MapFilter {
+    fn init(&self) -> Self {
+        Self {
+            key: self.key.to_lowercase(),
+            ..self.clone()
+        }
+    }
+
+    fn validate_header(&self, problems: &mut Vec<String>) {
+        if HeaderName::from_bytes(self.key.as_bytes()).is_err() {
+            problems.push(format!("invalid header name {:?}", self.key));
+        }
+    }
+
+    fn is_match(&self, value: &str) -> bool {
+        self.value.as_ref().is_none_or(|target| value == target)
+            && self.regex.as_ref().is_none_or(|re| re.is_match(value))
+    }
+}
+
+/// Accepts `200`, `2xx` and `200-299`.
+mod status_range {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::ops::RangeInclusive;
+
+    const STATUSES: RangeInclusive<u16> = 100..=599;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Status {
+        Code(u16),
+        Str(String),
+    }
+
+    pub fn serialize<S: Serializer>(
+        range: &RangeInclusive<u16>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{}-{}", range.start(), range.end()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<RangeInclusive<u16>, D::Error> {
+        let s = match Status::deserialize(deserializer)? {
+            Status::Code(code) => code.to_string(),
+            Status::Str(s) => s.to_lowercase(),
+        };
+        let invalid = || Error::custom(format!("invalid status: {s}"));
+        let range = match s.strip_suffix("xx") {
+            Some(class) => {
+                let class: u16 = class.parse().map_err(|_| invalid())?;
+                if !(1..=5).contains(&class) {
+                    return Err(invalid());
+                }
+                class * 100..=class * 100 + 99
+            }
+            None => {
+                let (start, end) = s.split_once('-').unwrap_or((&s, &s));
+                let start = start.trim().parse().map_err(|_| invalid())?;
+                let end = end.trim().parse().map_err(|_| invalid())?;
+                start..=end
+            }
+        };
+        if range.is_empty() || !STATUSES.contains(range.start()) || !STATUSES.contains(range.end())
+        {
+            return Err(invalid());
+        }
+        Ok(range)
+    }
+}
+
+/// Accepts both CIDR notation and a bare address for a single host.
+mod ip_net {
+    use ipnet::IpNet;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::net::IpAddr;
+
+    pub fn serialize<S: Serializer>(net: &IpNet, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(net)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpNet, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse::<IpNet>()
+            .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+            .map_err(Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Filter::{self, *},
+        ResponseFilter,
+    };
+    use crate::MitmHost;
+
+    fn patterns(filter: Filter) -> Vec<String> {
+        filter.mitm_filtter_pattern()
+    }
+
+    fn not(filter: Filter) -> Filter {
+        Not(Box::new(filter))
+    }
+
+    #[test]
+    fn all_of_ignores_not() {
+        let suffix = || DomainSuffix("example.com".to_owned());
+        let not_domain = || not(Domain("www.example.com".to_owned()));
+        assert_eq!(
+            patterns(AllOf(vec![suffix(), not_domain()])),
+            ["*example.com"]
+        );
+        assert_eq!(
+            patterns(AllOf(vec![not_domain(), suffix()])),
+            ["*example.com"]
+        );
+        assert_eq!(
+            patterns(AllOf(vec![not_domain(), Method("GET".to_owned())])),
+            ["*"]
+        );
+        assert_eq!(
+            patterns(AllOf(vec![
+                not_domain(),
+                AnyOf(vec![All, suffix()]),
+                not(not(Domain("a.com".to_owned())))
+            ])),
+            ["a.com"]
+        );
+    }
+
+    #[test]
+    fn keeps_domain_sets() {
+        let set = || DomainSet("hosts.txt".into());
+        let hosts = |filter: Filter| filter.mitm_hosts();
+        assert_eq!(
+            hosts(AllOf(vec![Method("GET".to_owned()), set()])),
+            [MitmHost::DomainSet("hosts.txt".into())]
+        );
+        assert_eq!(
+            hosts(AnyOf(vec![Domain("a.com".to_owned()), set()])),
+            [
+                MitmHost::Pattern("a.com".to_owned()),
+                MitmHost::DomainSet("hosts.txt".into())
+            ]
+        );
+        assert_eq!(hosts(not(set())), [MitmHost::Pattern("*".to_owned())]);
+    }
+
+    #[test]
+    fn status_ranges() {
+        let status = |json: &str| {
+            let json = format!(r#"{{"status": {json}}}"#);
+            match serde_json::from_str::<ResponseFilter>(&json) {
+                Ok(ResponseFilter::Status(range)) => Some(range),
+                Ok(_) => unreachable!(),
+                Err(_) => None,
+            }
+        };
+        assert_eq!(status("200"), Some(200..=200));
+        assert_eq!(status(r#""200""#), Some(200..=200));
+        assert_eq!(status(r#""2xx""#), Some(200..=299));
+        assert_eq!(status(r#""5XX""#), Some(500..=599));
+        assert_eq!(status(r#""200-299""#), Some(200..=299));
+        assert_eq!(status(r#""301 - 302""#), Some(301..=302));
+        for invalid in [
+            "99",
+            "600",
+            r#""0xx""#,
+            r#""6xx""#,
+            r#""700xx""#,
+            r#""299-200""#,
+            r#""200-""#,
+            r#""abc""#,
+        ] {
+            assert_eq!(status(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn any_of_needs_every_filter_restricted() {
+        let domain = |d: &str| Domain(d.to_owned());
+        assert_eq!(
+            patterns(AnyOf(vec![
+                domain("a.com"),
+                DomainSuffix(".b.com".to_owned())
+            ])),
+            ["a.com", "*.b.com"]
+        );
+        assert!(patterns(AnyOf(vec![domain("a.com"), Method("POST".to_owned())])).is_empty());
+        assert_eq!(patterns(AnyOf(vec![domain("a.com"), All])), ["*"]);
+        assert_eq!(
+            patterns(AnyOf(vec![domain("a.com"), not(domain("b.com"))])),
+            ["*"]
+        );
+        assert_eq!(
+            patterns(AllOf(vec![
+                DomainSuffix(".a.com".to_owned()),
+                AnyOf(vec![Method("POST".to_owned()), domain("b.com")])
+            ])),
+            ["*.a.com"]
+        );
+    }
+
+    #[test]
+    fn all_of_needs_a_host_filter() {
+        assert!(patterns(AllOf(vec![Method("GET".to_owned())])).is_empty());
+        assert!(patterns(AllOf(vec![not(All), Method("GET".to_owned())])).is_empty());
+        assert_eq!(
+            patterns(AllOf(vec![AnyOf(vec![All, Domain("a.com".to_owned())])])),
+            ["*"]
+        );
+    }
 }
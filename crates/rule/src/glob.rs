@@ -0,0 +1,44 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, sync::Arc};
+use wildmatch::WildMatch;
+
+/// A wildcard pattern with `*` and `?`, compiled when the rule is loaded, written as its
+/// pattern.
+///
+/// Clones share the compiled pattern.
+#[derive(Clone)]
+pub struct Glob(Arc<WildMatch>);
+
+impl Glob {
+    pub fn new(glob: &str) -> Self {
+        Self(Arc::new(WildMatch::new(glob)))
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        self.0.matches(text)
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Debug for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Glob").field(&self.to_string()).finish()
+    }
+}
+
+impl Serialize for Glob {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Glob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|glob| Self::new(&glob))
+    }
+}
@@ -1,16 +1,60 @@
-use crate::Rule;
+use crate::{
+    filter::{all_of, BufferedBody, ResolvedAddrs},
+    matcher::RuleMatcher,
+    Filter, Rule,
+};
 use async_trait::async_trait;
-use hyper::{header, Body, Request, Response};
-use log::info;
+use bytes::BytesMut;
+use futures_util::{future, stream, StreamExt};
+use hyper::{body::HttpBody, header, http::Extensions, Body, HeaderMap, Request, Response};
+use log::{info, warn};
 use mitm_core::{
+    error::Error,
     handler::{CustomContextData, HttpHandler},
     mitm::{HttpContext, RequestOrResponse},
+    HostResolver,
 };
-use std::sync::Arc;
+use std::{cmp::Reverse, collections::HashMap, sync::Arc};
+
+/// Larger bodies are not buffered for body filters, which then don't match.
+const MAX_BUFFERED_BODY: u64 = 16 * 1024 * 1024;
 
 #[derive(Clone)]
 pub struct RuleHttpHandler {
+    rules: Arc<RuleSet>,
+    user_rules: Arc<HashMap<String, Arc<RuleSet>>>,
+    resolver: HostResolver,
+}
+
+struct RuleSet {
     rules: Arc<Vec<Rule>>,
+    matcher: RuleMatcher,
+}
+
+impl RuleSet {
+    fn new(rules: Arc<Vec<Rule>>) -> Self {
+        // stable, equal priorities keep the load order
+        let mut rules = rules.as_ref().clone();
+        rules.sort_by_key(|rule| Reverse(rule.priority));
+        let matcher = RuleMatcher::new(&rules);
+        Self {
+            rules: Arc::new(rules),
+            matcher,
+        }
+    }
+
+    /// The matched rules in order, up to the first one with `stop`.
+    fn matched(&self, matched: &[usize]) -> Vec<Rule> {
+        let mut rules = vec![];
+        for i in matched {
+            let rule = &self.rules[*i];
+            rules.push(rule.clone());
+            if rule.stop {
+                break;
+            }
+        }
+        rules
+    }
 }
 
 #[derive(Default, Clone)]
@@ -22,20 +66,72 @@ impl CustomContextData for RuleHandlerCtx {}
 
 impl RuleHttpHandler {
     pub fn new(rules: Arc<Vec<Rule>>) -> Self {
-        Self { rules }
+        Self {
+            rules: Arc::new(RuleSet::new(rules)),
+            user_rules: Default::default(),
+            resolver: HostResolver::default(),
+        }
     }
 
-    fn match_rules(&self, req: &Request<Body>) -> Vec<Rule> {
-        let mut matched = vec![];
-        for rule in self.rules.iter() {
-            for filter in &rule.filters {
-                if filter.is_match_req(req) {
-                    matched.push(rule.clone());
-                }
-            }
+    /// Resolve hosts for `ip-cidr` filters with `resolver`, share the proxy's one to see the
+    /// same addresses as the MITM list.
+    pub fn with_resolver(mut self, resolver: HostResolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Use a dedicated rule set for requests from these authenticated users,
+    /// instead of the default rules.
+    pub fn with_user_rules(mut self, user_rules: HashMap<String, Arc<Vec<Rule>>>) -> Self {
+        let user_rules = user_rules
+            .into_iter()
+            .map(|(username, rules)| (username, Arc::new(RuleSet::new(rules))))
+            .collect();
+        self.user_rules = Arc::new(user_rules);
+        self
+    }
+
+    fn rules_for(&self, username: Option<&str>) -> &RuleSet {
+        username
+            .and_then(|username| self.user_rules.get(username))
+            .unwrap_or(&self.rules)
+    }
+}
+
+/// Resolve the host for `ip-cidr` filters.
+async fn resolve_host(req: &mut Request<Body>, resolver: &HostResolver) {
+    let Some(host) = req.uri().host() else {
+        return;
+    };
+    let addrs = resolver.resolve(host).await;
+    req.extensions_mut().insert(ResolvedAddrs(addrs));
+}
+
+/// Read the body into memory for body filters, up to `MAX_BUFFERED_BODY`. Larger bodies go on
+/// streaming, after the bytes already read.
+async fn buffer_body(
+    headers: &HeaderMap,
+    extensions: &mut Extensions,
+    mut body: Body,
+) -> Result<Body, hyper::Error> {
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > MAX_BUFFERED_BODY) {
+        return Ok(body);
+    }
+
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() as u64 > MAX_BUFFERED_BODY {
+            let read = stream::once(future::ready(Ok(buf.freeze())));
+            return Ok(Body::wrap_stream(read.chain(body)));
         }
-        matched
     }
+    let bytes = buf.freeze();
+    extensions.insert(BufferedBody(bytes.clone()));
+    Ok(Body::from(bytes))
 }
 
 #[async_trait]
@@ -51,7 +147,34 @@ impl HttpHandler<RuleHandlerCtx> for RuleHttpHandler {
         let mut req = req;
         req.headers_mut().remove(header::ACCEPT_ENCODING);
 
-        let rules = self.match_rules(&req);
+        let rule_set = self.rules_for(ctx.username.as_deref());
+        let mut matches = rule_set.matcher.eval(ctx, &req);
+        let needs_resolve = matches.undecided.iter().any(|i| {
+            rule_set.rules[*i]
+                .filters
+                .iter()
+                .any(|f| f.any(&|f| matches!(f, Filter::IpCidr(_))))
+        });
+        if needs_resolve {
+            resolve_host(&mut req, &self.resolver).await;
+            rule_set.matcher.eval_undecided(ctx, &req, &mut matches);
+        }
+        if !matches.undecided.is_empty() {
+            let (mut parts, body) = req.into_parts();
+            let body = match buffer_body(&parts.headers, &mut parts.extensions, body).await {
+                Ok(body) => body,
+                Err(err) => {
+                    warn!("failed to read request body for body filters: {err}");
+                    return RequestOrResponse::Response(
+                        Error::from(err).to_response(ctx.accept_json),
+                    );
+                }
+            };
+            req = Request::from_parts(parts, body);
+            rule_set.matcher.eval_undecided(ctx, &req, &mut matches);
+        }
+
+        let rules = rule_set.matched(&matches.matched);
         if !rules.is_empty() {
             ctx.should_modify_response = true;
         }
@@ -90,9 +213,171 @@ impl HttpHandler<RuleHandlerCtx> for RuleHttpHandler {
         );
 
         let mut res = res;
+        let needs_body =
+            ctx.custom_data.rules.iter().any(|rule| {
+                all_of(rule.response_filters.iter().map(|f| f.eval_res(&res))).is_none()
+            });
+        if needs_body {
+            let (mut parts, body) = res.into_parts();
+            let body = match buffer_body(&parts.headers, &mut parts.extensions, body).await {
+                Ok(body) => body,
+                Err(err) => {
+                    warn!("failed to read response body for body filters: {err}");
+                    return Error::from(err).to_response(ctx.accept_json);
+                }
+            };
+            res = Response::from_parts(parts, body);
+        }
+
         for rule in &ctx.custom_data.rules {
             res = rule.do_res(res).await;
         }
         res
     }
+
+    async fn handle_error(
+        &self,
+        ctx: &mut HttpContext<RuleHandlerCtx>,
+        err: &Error,
+    ) -> Response<Body> {
+        for rule in &ctx.custom_data.rules {
+            if let Some(res) = rule.do_err().await {
+                return res;
+            }
+        }
+        err.to_response(ctx.accept_json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn chunked(chunks: Vec<Result<Bytes, std::io::Error>>) -> Body {
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn buffers_chunked_body() {
+        let mut extensions = Extensions::new();
+        let body = chunked(vec![Ok("hello ".into()), Ok("world".into())]);
+        let body = buffer_body(&HeaderMap::new(), &mut extensions, body)
+            .await
+            .unwrap();
+        assert_eq!(extensions.get::<BufferedBody>().unwrap().0, "hello world");
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn streams_oversized_body() {
+        let chunk = Bytes::from(vec![b'a'; 1 << 20]);
+        let (mut sender, body) = Body::channel();
+        let (headers, mut extensions) = (HeaderMap::new(), Extensions::new());
+        let buffering = buffer_body(&headers, &mut extensions, body);
+        let sending = async {
+            for _ in 0..=MAX_BUFFERED_BODY >> 20 {
+                sender.send_data(chunk.clone()).await.unwrap();
+            }
+        };
+        // returns before the end of the stream
+        let (body, ()) = tokio::join!(buffering, sending);
+        let body = body.unwrap();
+        assert!(extensions.get::<BufferedBody>().is_none());
+
+        sender.send_data("end".into()).await.unwrap();
+        drop(sender);
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(bytes.len() as u64, MAX_BUFFERED_BODY + (1 << 20) + 3);
+        assert!(bytes.ends_with(b"aend"));
+    }
+
+    #[tokio::test]
+    async fn skips_large_content_length() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, (MAX_BUFFERED_BODY + 1).into());
+        let mut extensions = Extensions::new();
+        let body = buffer_body(&headers, &mut extensions, Body::from("small"))
+            .await
+            .unwrap();
+        assert!(extensions.get::<BufferedBody>().is_none());
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "small");
+    }
+
+    #[tokio::test]
+    async fn fails_on_read_error() {
+        let err = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let body = chunked(vec![Ok("partial".into()), Err(err)]);
+        let mut extensions = Extensions::new();
+        assert!(buffer_body(&HeaderMap::new(), &mut extensions, body)
+            .await
+            .is_err());
+        assert!(extensions.get::<BufferedBody>().is_none());
+    }
+
+    /// A rule matching `http://a0123.com/`, named by its keyword.
+    fn rule(keyword: &str, priority: i32, stop: bool) -> Rule {
+        Rule {
+            filters: vec![Filter::DomainKeyword(keyword.to_owned())],
+            response_filters: vec![],
+            actions: vec![crate::Action::LogReq],
+            priority,
+            stop,
+            url: None,
+        }
+    }
+
+    /// Keywords of the rules run for `http://a0123.com/`, in order.
+    async fn run(rules: Vec<Rule>) -> Vec<String> {
+        let handler = RuleHttpHandler::new(Arc::new(rules));
+        let mut ctx = HttpContext::default();
+        let req = Request::get("http://a0123.com/")
+            .body(Body::empty())
+            .unwrap();
+        handler.handle_request(&mut ctx, req).await;
+        ctx.custom_data
+            .rules
+            .iter()
+            .map(|rule| {
+                let keywords = rule.filters.iter().map(|f| match f {
+                    Filter::DomainKeyword(keyword) => keyword.as_str(),
+                    _ => unreachable!(),
+                });
+                keywords.collect::<Vec<_>>().join("+")
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn runs_by_priority() {
+        let rules = vec![
+            rule("0", 0, false),
+            rule("1", 5, false),
+            rule("2", 0, false),
+            rule("3", -1, false),
+            rule("nomatch", 9, false),
+            rule("a", 5, false),
+        ];
+        assert_eq!(run(rules).await, ["1", "a", "0", "2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_final() {
+        let rules = vec![
+            rule("0", 0, false),
+            rule("1", 1, true),
+            rule("2", 2, false),
+            rule("nomatch", 3, true),
+            rule("3", 1, false),
+        ];
+        assert_eq!(run(rules).await, ["2", "1"]);
+    }
+
+    #[tokio::test]
+    async fn runs_rules_once() {
+        let mut twice = rule("0", 0, false);
+        twice.filters.push(Filter::DomainKeyword("1".to_owned()));
+        let rules = vec![twice, rule("2", 0, false)];
+        assert_eq!(run(rules).await, ["0+1", "2"]);
+    }
 }
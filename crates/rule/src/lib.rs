@@ -1,25 +1,65 @@
 pub use action::Action;
-pub use filter::Filter;
+pub use filter::{BodyFilter, Filter, MapFilter, ResponseFilter, SizeRange};
+pub use glob::Glob;
 pub use handler::*;
 use hyper::{header, header::HeaderValue, Body, Request, Response, StatusCode};
 use log::*;
+pub use matcher::RuleMatcher;
+pub use mitm::{MitmHost, RuleMitmPolicy};
 use mitm_core::mitm::RequestOrResponse;
+pub use re::Regex;
 use std::vec::Vec;
 
 mod action;
 mod cache;
 mod filter;
+mod glob;
 mod handler;
+mod matcher;
+mod mitm;
+mod re;
+mod set;
 
 #[derive(Debug, Clone)]
 pub struct Rule {
     pub filters: Vec<Filter>,
+    /// All of them must match for the response actions to run.
+    pub response_filters: Vec<ResponseFilter>,
     pub actions: Vec<Action>,
+    /// Matched rules run from the highest priority, in load order when equal.
+    pub priority: i32,
+    /// No rule after this one runs when it matches.
+    pub stop: bool,
 
     pub url: Option<String>,
 }
 
 impl Rule {
+    /// Everything wrong with the rule that would otherwise only show up at request time.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        self.filters.iter().for_each(|f| f.validate(&mut problems));
+        self.response_filters
+            .iter()
+            .for_each(|f| f.validate(&mut problems));
+        for action in &self.actions {
+            action.validate(&mut problems);
+            if let Action::Redirect(target) = action {
+                if target.contains('$')
+                    && !self
+                        .filters
+                        .iter()
+                        .any(|f| matches!(f, Filter::UrlRegex(_)))
+                {
+                    problems.push(format!(
+                        "redirect target {target:?} needs a url-regex filter"
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
     pub async fn do_req(&mut self, req: Request<Body>) -> RequestOrResponse {
         let url = req.uri().to_string();
         self.url = Some(url.clone());
@@ -41,7 +81,7 @@ impl Rule {
                     if target.contains('$') {
                         for filter in self.filters.clone() {
                             if let Filter::UrlRegex(re) = filter {
-                                let target = cache::get_regex(&re)
+                                let target = re
                                     .replace(tmp_req.uri().to_string().as_str(), target.as_str())
                                     .to_string();
                                 if let Ok(target_url) = HeaderValue::from_str(target.as_str()) {
@@ -110,6 +150,10 @@ impl Rule {
 
     pub async fn do_res(&self, res: Response<Body>) -> Response<Body> {
         let url = self.url.clone().unwrap_or_default();
+        if !self.response_filters.iter().all(|f| f.is_match_res(&res)) {
+            debug!("[ResponseFilter] skip {}", url);
+            return res;
+        }
         let mut tmp_res = res;
 
         for action in &self.actions {
@@ -141,4 +185,18 @@ impl Rule {
 
         tmp_res
     }
+
+    /// The response to serve when the upstream request failed, if this rule has a fallback.
+    pub async fn do_err(&self) -> Option<Response<Body>> {
+        let url = self.url.clone().unwrap_or_default();
+        for action in &self.actions {
+            if let Action::Fallback(fallback) = action {
+                info!("[Fallback] {}", url);
+                if let Some(res) = fallback.response().await {
+                    return Some(res);
+                }
+            }
+        }
+        None
+    }
 }
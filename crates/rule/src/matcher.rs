@@ -0,0 +1,423 @@
+use aho_corasick::AhoCorasick;
+use hyper::{Body, Request};
+use log::warn;
+use mitm_core::{handler::CustomContextData, mitm::HttpContext};
+use regex::RegexSet;
+use std::collections::HashMap;
+
+use crate::{Filter, Regex, Rule};
+
+/// Matches a request against all rules in one pass, compiled when the rules are loaded.
+///
+/// Domains are looked up by hash, prefixes and suffixes by trie, keywords by Aho-Corasick
+/// and URL regexes by a [`RegexSet`]. Other filters are checked rule by rule.
+pub struct RuleMatcher {
+    rules: usize,
+    all: Vec<usize>,
+    domains: HashMap<String, Vec<usize>>,
+    prefixes: Trie,
+    suffixes: Trie,
+    keywords: Option<(AhoCorasick, Vec<usize>)>,
+    regexes: Option<(RegexSet, Vec<usize>)>,
+    /// Regexes `RegexSet` doesn't support, e.g. with look-around.
+    fancy_regexes: Vec<(Regex, usize)>,
+    others: Vec<(Filter, usize)>,
+}
+
+/// The result of matching a request.
+#[derive(Debug, Default)]
+pub(crate) struct Matches {
+    /// Indices of the matching rules, in order.
+    pub matched: Vec<usize>,
+    /// Indices of the rules depending on the resolved host or the body.
+    pub undecided: Vec<usize>,
+}
+
+impl RuleMatcher {
+    pub fn new(rules: &[Rule]) -> Self {
+        let mut matcher = Self {
+            rules: rules.len(),
+            all: vec![],
+            domains: HashMap::new(),
+            prefixes: Trie::default(),
+            suffixes: Trie::default(),
+            keywords: None,
+            regexes: None,
+            fancy_regexes: vec![],
+            others: vec![],
+        };
+        let mut keywords = (vec![], vec![]);
+        let mut regexes = (vec![], vec![]);
+
+        for (i, rule) in rules.iter().enumerate() {
+            for filter in &rule.filters {
+                match filter {
+                    Filter::All => matcher.all.push(i),
+                    Filter::Domain(d) => matcher.domains.entry(d.to_owned()).or_default().push(i),
+                    Filter::DomainPrefix(d) => matcher.prefixes.insert(d.bytes(), i),
+                    Filter::DomainSuffix(d) => matcher.suffixes.insert(d.bytes().rev(), i),
+                    Filter::DomainKeyword(d) => {
+                        keywords.0.push(d.to_owned());
+                        keywords.1.push(i);
+                    }
+                    Filter::UrlRegex(re) => {
+                        if regex::Regex::new(re.as_str()).is_ok() {
+                            regexes.0.push(re.clone());
+                            regexes.1.push(i);
+                        } else {
+                            matcher.fancy_regexes.push((re.clone(), i));
+                        }
+                    }
+                    filter => matcher.others.push((filter.to_owned(), i)),
+                }
+            }
+        }
+
+        if !keywords.0.is_empty() {
+            match AhoCorasick::new(&keywords.0) {
+                Ok(ac) => matcher.keywords = Some((ac, keywords.1)),
+                Err(err) => {
+                    warn!("failed to build the keyword matcher: {err}");
+                    for (keyword, i) in keywords.0.into_iter().zip(keywords.1) {
+                        matcher.others.push((Filter::DomainKeyword(keyword), i));
+                    }
+                }
+            }
+        }
+        if !regexes.0.is_empty() {
+            match RegexSet::new(regexes.0.iter().map(Regex::as_str)) {
+                Ok(set) => matcher.regexes = Some((set, regexes.1)),
+                // e.g. over the size limit
+                Err(err) => {
+                    warn!("failed to build the url-regex set: {err}");
+                    matcher
+                        .fancy_regexes
+                        .extend(regexes.0.into_iter().zip(regexes.1));
+                }
+            }
+        }
+        matcher
+    }
+
+    /// Indices of the rules matching the request, in order.
+    pub fn matches<D: CustomContextData>(
+        &self,
+        ctx: &HttpContext<D>,
+        req: &Request<Body>,
+    ) -> Vec<usize> {
+        self.eval(ctx, req).matched
+    }
+
+    pub(crate) fn eval<D: CustomContextData>(
+        &self,
+        ctx: &HttpContext<D>,
+        req: &Request<Body>,
+    ) -> Matches {
+        let mut matched = vec![false; self.rules];
+        let mut undecided = vec![false; self.rules];
+        let mut hit = |i: usize| matched[i] = true;
+
+        let host = req.uri().host().unwrap_or_default().to_lowercase();
+        self.all.iter().copied().for_each(&mut hit);
+        if let Some(rules) = self.domains.get(&host) {
+            rules.iter().copied().for_each(&mut hit);
+        }
+        self.prefixes.matches(host.bytes(), &mut hit);
+        self.suffixes.matches(host.bytes().rev(), &mut hit);
+        if let Some((ac, rules)) = &self.keywords {
+            for m in ac.find_overlapping_iter(&host) {
+                hit(rules[m.pattern().as_usize()]);
+            }
+        }
+        if self.regexes.is_some() || !self.fancy_regexes.is_empty() {
+            let url = req.uri().to_string();
+            if let Some((set, rules)) = &self.regexes {
+                set.matches(&url).iter().for_each(|m| hit(rules[m]));
+            }
+            for (re, i) in &self.fancy_regexes {
+                if !matched[*i] && re.is_match(&url) {
+                    matched[*i] = true;
+                }
+            }
+        }
+
+        for (filter, i) in &self.others {
+            if matched[*i] {
+                continue;
+            }
+            match filter.eval_req(ctx, req) {
+                Some(true) => matched[*i] = true,
+                Some(false) => {}
+                None => undecided[*i] = true,
+            }
+        }
+
+        let indices = |set: Vec<bool>| -> Vec<usize> {
+            set.into_iter()
+                .enumerate()
+                .filter_map(|(i, set)| set.then_some(i))
+                .collect()
+        };
+        let undecided = undecided
+            .iter()
+            .zip(&matched)
+            .map(|(undecided, matched)| *undecided && !matched)
+            .collect();
+        Matches {
+            matched: indices(matched),
+            undecided: indices(undecided),
+        }
+    }
+
+    /// Evaluate the undecided rules again, e.g. after the host is resolved or the body is
+    /// buffered. Only filters checked rule by rule can be undecided.
+    pub(crate) fn eval_undecided<D: CustomContextData>(
+        &self,
+        ctx: &HttpContext<D>,
+        req: &Request<Body>,
+        matches: &mut Matches,
+    ) {
+        let mut matched = vec![];
+        let mut undecided = vec![];
+        for (filter, i) in &self.others {
+            if !matches.undecided.contains(i) || matched.contains(i) {
+                continue;
+            }
+            match filter.eval_req(ctx, req) {
+                Some(true) => matched.push(*i),
+                Some(false) => {}
+                None => undecided.push(*i),
+            }
+        }
+        undecided.retain(|i| !matched.contains(i));
+        undecided.sort_unstable();
+        undecided.dedup();
+        matches.matched.extend(matched);
+        matches.matched.sort_unstable();
+        matches.undecided = undecided;
+    }
+}
+
+/// A byte trie, each node holding the rules whose pattern ends there.
+#[derive(Default)]
+struct Trie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Default)]
+struct Node {
+    children: Vec<(u8, usize)>,
+    rules: Vec<usize>,
+}
+
+impl Trie {
+    fn insert(&mut self, key: impl Iterator<Item = u8>, rule: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut node = 0;
+        for byte in key {
+            node = match self.child(node, byte) {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.push((byte, child));
+                    child
+                }
+            };
+        }
+        self.nodes[node].rules.push(rule);
+    }
+
+    /// Calls `hit` for the rules of every pattern `key` starts with.
+    fn matches(&self, key: impl Iterator<Item = u8>, mut hit: impl FnMut(usize)) {
+        let Some(root) = self.nodes.first() else {
+            return;
+        };
+        root.rules.iter().copied().for_each(&mut hit);
+        let mut node = 0;
+        for byte in key {
+            match self.child(node, byte) {
+                Some(child) => node = child,
+                None => return,
+            }
+            self.nodes[node].rules.iter().copied().for_each(&mut hit);
+        }
+    }
+
+    fn child(&self, node: usize, byte: u8) -> Option<usize> {
+        self.nodes[node]
+            .children
+            .iter()
+            .find(|(b, _)| *b == byte)
+            .map(|(_, child)| *child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::BufferedBody, Action, BodyFilter, RuleHandlerCtx};
+
+    fn rule(filters: Vec<Filter>) -> Rule {
+        Rule {
+            filters,
+            response_filters: vec![],
+            actions: vec![Action::LogReq],
+            priority: 0,
+            stop: false,
+            url: None,
+        }
+    }
+
+    fn req(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    /// The rules matching by `Filter::is_match_req`, one filter after another.
+    fn linear(
+        rules: &[Rule],
+        ctx: &HttpContext<RuleHandlerCtx>,
+        req: &Request<Body>,
+    ) -> Vec<usize> {
+        (0..rules.len())
+            .filter(|i| rules[*i].filters.iter().any(|f| f.is_match_req(ctx, req)))
+            .collect()
+    }
+
+    fn assert_same(rules: &[Rule], uris: &[&str]) {
+        let ctx = HttpContext::default();
+        let matcher = RuleMatcher::new(rules);
+        for uri in uris {
+            let req = req(uri);
+            assert_eq!(
+                matcher.matches(&ctx, &req),
+                linear(rules, &ctx, &req),
+                "{uri}"
+            );
+        }
+    }
+
+    #[test]
+    fn trie_edges() {
+        let rules = [
+            rule(vec![Filter::DomainSuffix("example.com".to_owned())]),
+            rule(vec![Filter::DomainSuffix(".example.com".to_owned())]),
+            rule(vec![Filter::DomainSuffix("com".to_owned())]),
+            rule(vec![Filter::DomainPrefix("api".to_owned())]),
+            rule(vec![Filter::DomainPrefix("api.".to_owned())]),
+            rule(vec![Filter::DomainPrefix(String::new())]),
+            rule(vec![Filter::Domain("example.com".to_owned())]),
+        ];
+        assert_same(
+            &rules,
+            &[
+                "http://example.com/",
+                "http://www.example.com/",
+                "http://aexample.com/",
+                "http://example.co/",
+                "http://com/",
+                "http://api/",
+                "http://ap/",
+                "http://api.example.com/",
+                "http://apis.example.org/",
+                "http://EXAMPLE.com/",
+            ],
+        );
+    }
+
+    #[test]
+    fn overlapping_keywords() {
+        let rules = [
+            rule(vec![Filter::DomainKeyword("track".to_owned())]),
+            rule(vec![Filter::DomainKeyword("tracker".to_owned())]),
+            rule(vec![Filter::DomainKeyword("rack".to_owned())]),
+            rule(vec![Filter::DomainKeyword("er.c".to_owned())]),
+            rule(vec![Filter::DomainKeyword("ads".to_owned())]),
+        ];
+        assert_same(
+            &rules,
+            &[
+                "http://tracker.com/",
+                "http://rack.ads.com/",
+                "http://trac.com/",
+                "http://tracks/",
+            ],
+        );
+        let matcher = RuleMatcher::new(&rules);
+        let ctx = HttpContext::<RuleHandlerCtx>::default();
+        assert_eq!(
+            matcher.matches(&ctx, &req("http://tracker.com/")),
+            [0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn fancy_regex_fallback() {
+        let url_regex = |re: &str| Filter::UrlRegex(Regex::new(re).unwrap());
+        let rules = [
+            rule(vec![url_regex(r"^https://(?!www\.)[^/]+/ads")]),
+            rule(vec![url_regex(r"/ads$")]),
+            rule(vec![url_regex(r"(\w+)\.\1")]),
+        ];
+        let matcher = RuleMatcher::new(&rules);
+        assert_eq!(matcher.fancy_regexes.len(), 2);
+        assert_same(
+            &rules,
+            &[
+                "https://www.example.com/ads",
+                "https://cdn.example.com/ads",
+                "https://cdn.cdn.com/",
+                "https://a.b/",
+            ],
+        );
+    }
+
+    #[test]
+    fn others_and_not() {
+        let rules = [
+            rule(vec![Filter::Not(Box::new(Filter::Domain(
+                "a.com".to_owned(),
+            )))]),
+            rule(vec![
+                Filter::Method("POST".to_owned()),
+                Filter::DomainSuffix("b.com".to_owned()),
+            ]),
+            rule(vec![Filter::AllOf(vec![
+                Filter::DomainSuffix("com".to_owned()),
+                Filter::Not(Box::new(Filter::DomainKeyword("b".to_owned()))),
+            ])]),
+        ];
+        assert_same(&rules, &["http://a.com/", "http://b.com/", "http://c.com/"]);
+    }
+
+    #[test]
+    fn reevaluates_undecided() {
+        let body = |contains: &str| {
+            Filter::Body(BodyFilter {
+                contains: Some(contains.to_owned()),
+                ..Default::default()
+            })
+        };
+        let rules = [
+            rule(vec![Filter::Not(Box::new(body("secret")))]),
+            rule(vec![body("token"), Filter::Domain("a.com".to_owned())]),
+            rule(vec![body("token")]),
+            rule(vec![Filter::AnyOf(vec![body("none"), Filter::All])]),
+            rule(vec![Filter::Domain("b.com".to_owned())]),
+        ];
+        let ctx = HttpContext::<RuleHandlerCtx>::default();
+        let matcher = RuleMatcher::new(&rules);
+        let mut req = req("http://a.com/");
+        let mut matches = matcher.eval(&ctx, &req);
+        assert_eq!(matches.matched, [1, 3]);
+        assert_eq!(matches.undecided, [0, 2]);
+
+        req.extensions_mut().insert(BufferedBody("token=1".into()));
+        matcher.eval_undecided(&ctx, &req, &mut matches);
+        assert_eq!(matches.matched, [0, 1, 2, 3]);
+        assert!(matches.undecided.is_empty());
+        assert_eq!(matches.matched, linear(&rules, &ctx, &req));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{
+    cache::get_domain_set,
+    set::{DomainSet, FileSet},
+};
+use async_trait::async_trait;
+use mitm_core::{
+    handler::{CustomContextData, MitmList, MitmPolicy},
+    mitm::HttpContext,
+    HostResolver,
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+/// Hosts a rule needs intercepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MitmHost {
+    /// A [`MitmList`] pattern.
+    Pattern(String),
+    /// The hosts of a `domain-set` file.
+    DomainSet(PathBuf),
+}
+
+/// Intercepts the hosts of the rules. `domain-set` files are asked directly, so a reload
+/// takes effect at once, removed hosts included.
+#[derive(Clone, Default)]
+pub struct RuleMitmPolicy {
+    list: MitmList,
+    domain_sets: Vec<Arc<FileSet<DomainSet>>>,
+}
+
+impl RuleMitmPolicy {
+    pub fn new(hosts: Vec<MitmHost>) -> Self {
+        let mut patterns = vec![];
+        let mut paths = vec![];
+        for host in hosts {
+            match host {
+                MitmHost::Pattern(pattern) => patterns.push(pattern),
+                MitmHost::DomainSet(path) => paths.push(path),
+            }
+        }
+        paths.sort();
+        paths.dedup();
+        Self {
+            list: MitmList::new(patterns),
+            domain_sets: paths.iter().map(|path| get_domain_set(path)).collect(),
+        }
+    }
+
+    /// Resolve hosts for CIDRs with `resolver`.
+    pub fn with_resolver(mut self, resolver: HostResolver) -> Self {
+        self.list = self.list.with_resolver(resolver);
+        self
+    }
+
+    /// The patterns, clones share them.
+    pub fn list(&self) -> &MitmList {
+        &self.list
+    }
+
+    /// Whether `host` matches a pattern or is in a domain set, without resolving it.
+    pub fn matches(&self, host: &str) -> bool {
+        self.in_domain_sets(host) || self.list.matches(host)
+    }
+
+    fn in_domain_sets(&self, host: &str) -> bool {
+        if self.domain_sets.is_empty() {
+            return false;
+        }
+        let host = host.to_lowercase();
+        self.domain_sets.iter().any(|set| set.get().contains(&host))
+    }
+}
+
+#[async_trait]
+impl<D: CustomContextData> MitmPolicy<D> for RuleMitmPolicy {
+    async fn should_intercept(
+        &self,
+        _ctx: &HttpContext<D>,
+        host: &str,
+        _client_addr: Option<SocketAddr>,
+    ) -> bool {
+        self.in_domain_sets(host) || self.list.matches_resolved(host).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::Duration};
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_domain_set_reloads() {
+        let path = std::env::temp_dir().join(format!("good-mitm-mitm-{}", std::process::id()));
+        fs::write(&path, "a.com\n.b.com\n").unwrap();
+        let policy = RuleMitmPolicy::new(vec![
+            MitmHost::Pattern("c.com".to_owned()),
+            MitmHost::DomainSet(path.clone()),
+        ]);
+        assert!(policy.matches("A.com"));
+        assert!(policy.matches("x.b.com"));
+        assert!(policy.matches("c.com"));
+        assert!(!policy.matches("d.com"));
+
+        // the modification time has to change
+        tokio::time::advance(Duration::from_secs(1)).await;
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(&path, "d.com\n").unwrap();
+        for _ in 0..50 {
+            tokio::time::advance(Duration::from_secs(5)).await;
+            tokio::task::yield_now().await;
+            if policy.matches("d.com") {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(policy.matches("d.com"));
+        assert!(!policy.matches("a.com"));
+        assert!(!policy.matches("x.b.com"));
+    }
+}
@@ -0,0 +1,50 @@
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{borrow::Cow, fmt, sync::Arc};
+
+/// A regular expression compiled when the rule is loaded, written as its pattern.
+///
+/// Clones share the compiled regex.
+#[derive(Clone)]
+pub struct Regex(Arc<fancy_regex::Regex>);
+
+impl Regex {
+    pub fn new(re: &str) -> Result<Self, fancy_regex::Error> {
+        fancy_regex::Regex::new(re).map(|re| Self(Arc::new(re)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Exceeding the backtrack limit counts as no match.
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text).unwrap_or_default()
+    }
+
+    pub fn replace<'t>(&self, text: &'t str, rep: &str) -> Cow<'t, str> {
+        self.0.replace(text, rep)
+    }
+
+    pub fn replace_all<'t>(&self, text: &'t str, rep: &str) -> Cow<'t, str> {
+        self.0.replace_all(text, rep)
+    }
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Regex").field(&self.as_str()).finish()
+    }
+}
+
+impl Serialize for Regex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Regex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let re = String::deserialize(deserializer)?;
+        Self::new(&re).map_err(|err| Error::custom(format!("invalid regex {re}: {err}")))
+    }
+}
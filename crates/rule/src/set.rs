@@ -0,0 +1,214 @@
+use fancy_regex::Regex;
+use log::{info, warn};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
+};
+use tokio::{runtime::Handle, sync::watch, time};
+
+/// How often the file is checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A set loaded from a file, one entry per line, and reloaded in the background when the
+/// file changes.
+pub struct FileSet<T> {
+    path: PathBuf,
+    set: watch::Sender<Arc<T>>,
+}
+
+pub trait ParseSet: Default + Send + Sync + 'static {
+    fn parse(content: &str) -> Self;
+
+    /// Problems of the entries `parse` skips.
+    fn validate(_content: &str) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// Problems of the file at `path`, which would otherwise load as an empty or partial set.
+pub(crate) fn validate_file<T: ParseSet>(path: &Path, problems: &mut Vec<String>) {
+    match fs::read_to_string(path) {
+        Ok(content) => problems.extend(
+            T::validate(&content)
+                .into_iter()
+                .map(|problem| format!("{}: {problem}", path.display())),
+        ),
+        Err(err) => problems.push(format!("failed to read {}: {err}", path.display())),
+    }
+}
+
+impl<T: ParseSet> FileSet<T> {
+    /// Reloading needs a Tokio runtime, without one the file is only loaded once.
+    pub fn new(path: &Path) -> Arc<Self> {
+        let (modified, set) = match load(path) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                warn!("[Set] failed to load {}: {}", path.display(), err);
+                (None, T::default())
+            }
+        };
+        let (set, _) = watch::channel(Arc::new(set));
+        let file_set = Arc::new(Self {
+            path: path.to_owned(),
+            set,
+        });
+        if let Ok(runtime) = Handle::try_current() {
+            runtime.spawn(reload(Arc::downgrade(&file_set), modified));
+        }
+        file_set
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.set.borrow().clone()
+    }
+}
+
+async fn reload<T: ParseSet>(file_set: Weak<FileSet<T>>, mut modified: Option<SystemTime>) {
+    let mut interval = time::interval(CHECK_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(file_set) = file_set.upgrade() else {
+            return;
+        };
+        let path = &file_set.path;
+        let current = tokio::fs::metadata(path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        if current == modified {
+            continue;
+        }
+        modified = current;
+        match tokio::fs::read_to_string(path).await {
+            Ok(content) => {
+                info!("[Set] reloaded {}", path.display());
+                file_set.set.send_replace(Arc::new(T::parse(&content)));
+            }
+            Err(err) => warn!("[Set] failed to reload {}: {}", path.display(), err),
+        }
+    }
+}
+
+fn load<T: ParseSet>(path: &Path) -> io::Result<(Option<SystemTime>, T)> {
+    let modified = fs::metadata(path)?.modified().ok();
+    let content = fs::read_to_string(path)?;
+    Ok((modified, T::parse(&content)))
+}
+
+/// Non-empty lines which aren't `#` comments.
+fn entries(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// `example.com` matches the domain, `.example.com` also its subdomains and
+/// `keyword:example` any domain containing it.
+#[derive(Debug, Default)]
+pub struct DomainSet {
+    domains: HashSet<String>,
+    suffixes: HashSet<String>,
+    keywords: Vec<String>,
+}
+
+impl ParseSet for DomainSet {
+    fn parse(content: &str) -> Self {
+        let mut set = Self::default();
+        for entry in entries(content).map(str::to_lowercase) {
+            if let Some(keyword) = entry.strip_prefix("keyword:") {
+                set.keywords.push(keyword.to_owned());
+            } else if let Some(suffix) = entry.strip_prefix('.') {
+                set.suffixes.insert(suffix.to_owned());
+            } else {
+                set.domains.insert(entry);
+            }
+        }
+        set
+    }
+}
+
+impl DomainSet {
+    /// `host` has to be lowercase.
+    pub fn contains(&self, host: &str) -> bool {
+        if self.domains.contains(host) {
+            return true;
+        }
+        let mut domain = host;
+        loop {
+            if self.suffixes.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => break,
+            }
+        }
+        self.keywords.iter().any(|keyword| host.contains(keyword))
+    }
+
+    pub fn mitm_patterns(&self) -> Vec<String> {
+        let domains = self.domains.iter().cloned();
+        let suffixes = self
+            .suffixes
+            .iter()
+            .flat_map(|suffix| [suffix.to_owned(), format!("*.{suffix}")]);
+        let keywords = self.keywords.iter().map(|keyword| format!("*{keyword}*"));
+        domains.chain(suffixes).chain(keywords).collect()
+    }
+}
+
+/// Regular expressions matched against the entire URL.
+#[derive(Debug, Default)]
+pub struct UrlRegexSet {
+    regexes: Vec<Regex>,
+}
+
+impl ParseSet for UrlRegexSet {
+    fn parse(content: &str) -> Self {
+        let regexes = entries(content)
+            .filter_map(|re| {
+                Regex::new(re)
+                    .map_err(|err| warn!("[Set] invalid regex {re}: {err}"))
+                    .ok()
+            })
+            .collect();
+        Self { regexes }
+    }
+
+    fn validate(content: &str) -> Vec<String> {
+        entries(content)
+            .filter_map(|re| Some(format!("invalid regex {re:?}: {}", Regex::new(re).err()?)))
+            .collect()
+    }
+}
+
+impl UrlRegexSet {
+    pub fn is_match(&self, url: &str) -> bool {
+        self.regexes
+            .iter()
+            .any(|re| re.is_match(url).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_files() {
+        let regexes = "# comment\n^https://(?!www)\n[bad\n";
+        let problems = UrlRegexSet::validate(regexes);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("invalid regex \"[bad\""));
+        assert_eq!(UrlRegexSet::parse(regexes).regexes.len(), 1);
+
+        let mut problems = vec![];
+        validate_file::<DomainSet>(Path::new("missing-domain-set.txt"), &mut problems);
+        assert!(problems[0].starts_with("failed to read missing-domain-set.txt"));
+    }
+}
@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct User {
+    pub username: String,
+    pub password: String,
+    /// Rule file or dir used instead of the default rules for this user.
+    #[serde(alias = "rules")]
+    pub rule: Option<String>,
+}
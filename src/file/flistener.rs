@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+use super::SingleOrMulti;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    #[default]
+    Proxy,
+    Transparent,
+    Reverse,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Listener {
+    pub bind: String,
+    #[serde(default)]
+    pub mode: Mode,
+    /// Credentials file, same format as `--auth`.
+    pub auth: Option<String>,
+    #[serde(alias = "mitm")]
+    pub mitm_list: Option<SingleOrMulti<String>>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Backend origin for reverse mode.
+    pub backend: Option<String>,
+    /// Certificate and key files for HTTPS in reverse mode.
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// Expect a PROXY protocol header from a load balancer.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
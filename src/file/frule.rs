@@ -9,11 +9,17 @@ pub struct Rule {
     pub mitm_list: Option<SingleOrMulti<String>>,
     #[serde(alias = "filter")]
     pub filters: SingleOrMulti<rule::Filter>,
+    #[serde(default, alias = "response-filter", alias = "response-filters")]
+    pub response_filters: Option<SingleOrMulti<rule::ResponseFilter>>,
     #[serde(alias = "action")]
     pub actions: SingleOrMulti<rule::Action>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default, alias = "final")]
+    pub stop: bool,
 }
 
-impl From<Rule> for (rule::Rule, Vec<String>) {
+impl From<Rule> for (rule::Rule, Vec<rule::MitmHost>) {
     fn from(rule: Rule) -> Self {
         let filters: Vec<rule::Filter> = rule
             .filters
@@ -22,20 +28,34 @@ impl From<Rule> for (rule::Rule, Vec<String>) {
             .map(rule::Filter::init)
             .collect();
 
-        let mut mitm_filters: Vec<String> = filters
-            .iter()
-            .filter_map(rule::Filter::mitm_filtter_pattern)
-            .collect();
+        // the rule matches when any filter does
+        let mut mitm_filters = rule::Filter::AnyOf(filters.clone()).mitm_hosts();
 
         let mut mitm_list_2 = match rule.mitm_list {
-            Some(s) => s.into_vec().into_iter().collect(),
+            Some(s) => s
+                .into_vec()
+                .into_iter()
+                .map(rule::MitmHost::Pattern)
+                .collect(),
             None => vec![],
         };
         mitm_filters.append(&mut mitm_list_2);
 
         let rule = rule::Rule {
             filters,
+            response_filters: rule
+                .response_filters
+                .map(|filters| {
+                    filters
+                        .into_vec()
+                        .iter()
+                        .map(rule::ResponseFilter::init)
+                        .collect()
+                })
+                .unwrap_or_default(),
             actions: rule.actions.into_vec(),
+            priority: rule.priority,
+            stop: rule.stop,
             url: None,
         };
 
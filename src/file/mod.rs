@@ -1,69 +1,212 @@
-use anyhow::Result;
-use log::error;
+use anyhow::{bail, Result};
+use log::{error, warn};
 use single_multi::SingleOrMulti;
-use std::{fs, io::BufReader, path::Path};
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
 
+pub mod fauth;
+pub mod flistener;
 pub mod frule;
 mod single_multi;
 
-pub fn load_rules_amd_mitm_filters<P: AsRef<Path> + Clone>(
+/// Loads the rules of a file, or of all `.yaml` and `.yml` files in a directory by name,
+/// warning about other files and subdirectories.
+///
+/// Every rule is validated, all problems are logged and fail the loading.
+pub fn load_rules_amd_mitm_filters<P: AsRef<Path>>(
     path: P,
-) -> Result<(Vec<rule::Rule>, Vec<String>)> {
-    let m = fs::metadata(&path).expect("Not a valid path");
-    if m.file_type().is_dir() {
-        load_rules_amd_mitm_filters_from_dir(path)
+) -> Result<(Vec<rule::Rule>, Vec<rule::MitmHost>)> {
+    let path = path.as_ref();
+    let files = if fs::metadata(path)?.is_dir() {
+        let mut files: Vec<PathBuf> = vec![];
+        for file in fs::read_dir(path)?.flatten().map(|f| f.path()) {
+            let is_yaml = file
+                .extension()
+                .is_some_and(|ext| ext == "yaml" || ext == "yml");
+            if file.is_dir() {
+                warn!(
+                    "skip {}, subdirectories of rules aren't loaded",
+                    file.display()
+                );
+            } else if !is_yaml {
+                warn!(
+                    "skip {}, only .yaml and .yml rules are loaded",
+                    file.display()
+                );
+            } else {
+                files.push(file);
+            }
+        }
+        files.sort();
+        files
     } else {
-        load_rules_amd_mitm_filters_from_file(path)
+        vec![path.to_owned()]
+    };
+
+    let mut rules = vec![];
+    let mut mitm_filters = vec![];
+    let mut problems = vec![];
+    for file in files {
+        load_rules_amd_mitm_filters_from_file(&file, &mut rules, &mut mitm_filters, &mut problems);
     }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("{problem}");
+        }
+        bail!("{} problems in rules {}", problems.len(), path.display());
+    }
+    Ok((rules, mitm_filters))
 }
 
-fn load_rules_amd_mitm_filters_from_file<P: AsRef<Path> + Clone>(
-    path: P,
-) -> Result<(Vec<rule::Rule>, Vec<String>)> {
-    let file = fs::File::open(path.clone())?;
+fn load_rules_amd_mitm_filters_from_file(
+    path: &Path,
+    rules: &mut Vec<rule::Rule>,
+    mitm_filters: &mut Vec<rule::MitmHost>,
+    problems: &mut Vec<String>,
+) {
+    let path_str = path.display();
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => return problems.push(format!("{path_str}: {err}")),
+    };
+    // syntax errors come with their location
+    let values: Vec<serde_yaml::Value> = match serde_yaml::from_str(&content) {
+        Ok(values) => values,
+        Err(err) => return problems.push(format!("{path_str}: {err}")),
+    };
+
+    // rule by rule to report every invalid one, padded to keep the line numbers
+    let offsets = rule_offsets(&content);
+    let chunks: Vec<(Option<usize>, String)> = if offsets.len() == values.len() {
+        let ends = offsets.iter().skip(1).copied().chain([content.len()]);
+        offsets
+            .iter()
+            .zip(ends)
+            .map(|(&start, end)| {
+                let line = content[..start].lines().count();
+                let chunk = format!("{}{}", "\n".repeat(line), &content[start..end]);
+                (Some(line + 1), chunk)
+            })
+            .collect()
+    } else {
+        vec![(None, content.clone())]
+    };
+
+    for (i, (line, chunk)) in chunks.into_iter().enumerate() {
+        let location = match line {
+            Some(line) => format!("{path_str}:{line}"),
+            None => path_str.to_string(),
+        };
+        let file_rules: Vec<frule::Rule> = match serde_yaml::from_str(&chunk) {
+            Ok(file_rules) => file_rules,
+            Err(err) => {
+                let name = line
+                    .and_then(|_| values[i].get("name")?.as_str())
+                    .map(|name| format!(" rule {name}:"))
+                    .unwrap_or_default();
+                problems.push(format!("{location}:{name} {err}"));
+                continue;
+            }
+        };
+
+        for rule in file_rules {
+            let name = rule.name.clone();
+            let (rule, mut filters): (rule::Rule, _) = rule.into();
+            let rule_problems = rule.validate();
+            if rule_problems.is_empty() {
+                rules.push(rule);
+                mitm_filters.append(&mut filters);
+            } else {
+                problems.extend(
+                    rule_problems
+                        .into_iter()
+                        .map(|problem| format!("{location}: rule {name}: {problem}")),
+                );
+            }
+        }
+    }
+}
+
+/// Byte offsets of the top level `- ` items.
+fn rule_offsets(content: &str) -> Vec<usize> {
+    let mut offset = 0;
+    let mut offsets = vec![];
+    for line in content.split_inclusive('\n') {
+        if line.starts_with("- ") || line.trim_end() == "-" {
+            offsets.push(offset);
+        }
+        offset += line.len();
+    }
+    offsets
+}
+
+pub fn load_users<P: AsRef<Path>>(path: P) -> Result<Vec<fauth::User>> {
+    let file = fs::File::open(path.as_ref())?;
     let reader = BufReader::new(file);
-    let rules: Vec<frule::Rule> = match serde_yaml::from_reader(reader) {
-        Ok(rules) => rules,
+    match serde_yaml::from_reader(reader) {
+        Ok(users) => Ok(users),
         Err(err) => {
             error!(
-                "load rule ({}) failed: {err}",
+                "load credentials ({}) failed: {err}",
                 path.as_ref().to_str().unwrap()
             );
-            return Err(err.into());
+            Err(err.into())
         }
-    };
-
-    let (rules, filters) = rules
-        .into_iter()
-        .fold((vec![], vec![]), |(mut a, mut b), r| {
-            let (rule, mut filters) = r.into();
-            a.push(rule);
-            b.append(&mut filters);
-            (a, b)
-        });
+    }
+}
 
-    Ok((rules, filters))
+pub fn load_listeners<P: AsRef<Path>>(path: P) -> Result<Vec<flistener::Listener>> {
+    let file = fs::File::open(path.as_ref())?;
+    let reader = BufReader::new(file);
+    match serde_yaml::from_reader(reader) {
+        Ok(listeners) => Ok(listeners),
+        Err(err) => {
+            error!(
+                "load listeners ({}) failed: {err}",
+                path.as_ref().to_str().unwrap()
+            );
+            Err(err.into())
+        }
+    }
 }
 
-fn load_rules_amd_mitm_filters_from_dir<P: AsRef<Path>>(
-    path: P,
-) -> Result<(Vec<rule::Rule>, Vec<String>)> {
-    let dir = fs::read_dir(path).expect("Not a valid dir");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let (rules, filters) = dir
-        .flatten()
-        .filter(|f| f.file_type().is_ok())
-        .filter(|f| f.file_type().ok().unwrap().is_file())
-        .map(|f| load_rules_amd_mitm_filters_from_file(f.path()))
-        .filter_map(|r| r.ok())
-        .fold(
-            (vec![], vec![]),
-            |(mut a, mut b), (mut rule, mut filters)| {
-                a.append(&mut rule);
-                b.append(&mut filters);
-                (a, b)
-            },
-        );
+    #[test]
+    fn loads_files_in_name_order() {
+        let dir = std::env::temp_dir().join(format!("good-mitm-rules-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let rule = |domain: &str| {
+            format!("- name: {domain}\n  filter:\n    domain: {domain}\n  action: log-req\n")
+        };
+        fs::write(dir.join("b.yaml"), rule("b.com") + &rule("c.com")).unwrap();
+        fs::write(dir.join("a.yml"), rule("a.com")).unwrap();
+        fs::write(dir.join("sub/d.yaml"), rule("d.com")).unwrap();
+        fs::write(dir.join("e.txt"), "not rules").unwrap();
 
-    Ok((rules, filters))
+        let (rules, mitm_filters) = load_rules_amd_mitm_filters(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let domains: Vec<_> = rules
+            .iter()
+            .map(|rule| match &rule.filters[..] {
+                [rule::Filter::Domain(domain)] => domain.as_str(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(domains, ["a.com", "b.com", "c.com"]);
+        let mitm_filters: Vec<_> = mitm_filters
+            .into_iter()
+            .map(|host| match host {
+                rule::MitmHost::Pattern(pattern) => pattern,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(mitm_filters, ["a.com", "b.com", "c.com"]);
+    }
 }
@@ -1,7 +1,10 @@
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{DeserializeOwned, Error},
+    Deserialize, Deserializer, Serialize,
+};
 use std::vec::Vec;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum SingleOrMulti<T> {
     Single(T),
@@ -22,3 +25,17 @@ impl<T> From<SingleOrMulti<T>> for Vec<T> {
         }
     }
 }
+
+// Like untagged, buffering the value to accept enums written as maps, but keeping
+// the error of why it didn't match.
+impl<'de, T: DeserializeOwned> Deserialize<'de> for SingleOrMulti<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if value.is_array() {
+            serde_json::from_value(value).map(SingleOrMulti::Multi)
+        } else {
+            serde_json::from_value(value).map(SingleOrMulti::Single)
+        }
+        .map_err(D::Error::custom)
+    }
+}
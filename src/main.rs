@@ -1,13 +1,18 @@
 #![allow(dead_code)]
 
-use anyhow::{Ok, Result};
+use anyhow::{bail, Ok, Result};
 use clap::Parser;
+use file::flistener;
 use hyper_proxy::Intercept;
 use log::*;
-use mitm_core::{CertificateAuthority, Proxy};
-use rule::RuleHttpHandler;
+use mitm_core::{
+    handler::MitmList, ipnet::IpNet, AccessControl, CertificateAuthority, HostResolver, KeepAlive,
+    ListenAddr, Listener, ListenerMode, Passthrough, Proxy, ProxyAuth, ProxyProtocolVersion,
+    ReverseProxy, Timeouts, TlsMirror,
+};
+use rule::{MitmHost, Rule, RuleHttpHandler, RuleMitmPolicy};
 use rustls_pemfile as pemfile;
-use std::{fs, sync::Arc};
+use std::{collections::HashMap, fs, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use good_mitm::*;
 
@@ -18,6 +23,7 @@ struct AppOpts {
     subcmd: SubCommand,
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser)]
 enum SubCommand {
     /// run proxy serve
@@ -39,10 +45,84 @@ struct Run {
     cert: String,
     #[clap(short, long, help = "load rules from file or dir")]
     rule: String,
-    #[clap(short, long, default_value = "127.0.0.1:34567", help = "bind address")]
-    bind: String,
+    #[clap(short, long, help = "bind address [default: 127.0.0.1:34567]")]
+    bind: Option<String>,
+    #[clap(short, long, help = "load listeners from file")]
+    listeners: Option<String>,
     #[clap(short, long, help = "upstream proxy")]
     proxy: Option<String>,
+    #[clap(short, long, help = "proxy auth credentials file")]
+    auth: Option<String>,
+    #[clap(long, value_parser = parse_ip_net, help = "allowed client ip or cidr")]
+    allow: Vec<IpNet>,
+    #[clap(long, value_parser = parse_ip_net, help = "denied client ip or cidr")]
+    deny: Vec<IpNet>,
+    #[clap(long, value_parser = parse_unix_route, help = "dial unix socket for host, HOST=PATH")]
+    unix_route: Vec<(String, PathBuf)>,
+    #[clap(
+        long,
+        value_parser = parse_proxy_protocol,
+        conflicts_with = "proxy",
+        help = "send PROXY protocol header upstream, v1 or v2"
+    )]
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
+    #[clap(long, help = "upstream connect timeout in seconds")]
+    connect_timeout: Option<u64>,
+    #[clap(
+        long,
+        default_value_t = 5,
+        help = "client tls handshake timeout in seconds"
+    )]
+    tls_handshake_timeout: u64,
+    #[clap(long, help = "client request header read timeout in seconds")]
+    header_read_timeout: Option<u64>,
+    #[clap(long, help = "upstream response timeout in seconds")]
+    upstream_timeout: Option<u64>,
+    #[clap(long, help = "close client connections idle for seconds")]
+    idle_timeout: Option<u64>,
+    #[clap(long, help = "maximum tunnel lifetime in seconds")]
+    tunnel_timeout: Option<u64>,
+    #[clap(long, help = "close client connections after each request")]
+    no_keep_alive: bool,
+    #[clap(long, help = "close pooled upstream connections idle for seconds")]
+    pool_idle_timeout: Option<u64>,
+    #[clap(long, help = "maximum idle upstream connections per host")]
+    pool_max_idle: Option<usize>,
+    #[clap(
+        long,
+        help = "tunnel hosts after the client rejected the certificate this many times"
+    )]
+    passthrough_threshold: Option<u32>,
+    #[clap(long, default_value_t = 600, help = "tunnel such hosts for seconds")]
+    passthrough_cooldown: u64,
+    #[clap(
+        long,
+        help = "offer upstream what the client offered in its ClientHello for matching hosts"
+    )]
+    mirror_tls: Vec<String>,
+}
+
+const DEFAULT_BIND: &str = "127.0.0.1:34567";
+
+fn parse_proxy_protocol(s: &str) -> Result<ProxyProtocolVersion, String> {
+    match s {
+        "v1" | "1" => Some(ProxyProtocolVersion::V1),
+        "v2" | "2" => Some(ProxyProtocolVersion::V2),
+        _ => None,
+    }
+    .ok_or_else(|| "expect v1 or v2".to_owned())
+}
+
+fn parse_unix_route(s: &str) -> Result<(String, PathBuf), String> {
+    s.split_once('=')
+        .map(|(host, path)| (host.to_owned(), path.into()))
+        .ok_or_else(|| "expect HOST=PATH".to_owned())
+}
+
+fn parse_ip_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|err| err.to_string())
 }
 
 #[derive(Parser)]
@@ -57,7 +137,10 @@ fn main() {
     let opts = AppOpts::parse();
     match opts.subcmd {
         SubCommand::Run(opts) => {
-            run(&opts).unwrap();
+            if let Err(err) = run(&opts) {
+                error!("{err:#}");
+                std::process::exit(1);
+            }
         }
         SubCommand::Genca(opts) => {
             #[allow(unused_variables)]
@@ -92,29 +175,186 @@ async fn run(opts: &Run) -> Result<()> {
     )
     .expect("Failed to create Certificate Authority");
 
-    info!("Http Proxy listen on: http://{}", opts.bind);
-
-    let (rules, mitm_filters) = file::load_rules_amd_mitm_filters(&opts.rule)?;
+    let (rules, mut mitm_filters) = file::load_rules_amd_mitm_filters(&opts.rule)?;
     let rules = Arc::new(rules);
-    let http_handler = RuleHttpHandler::new(rules);
+    let mut user_rules = HashMap::new();
+    let mut users = HashMap::new();
+    let mut listeners = vec![];
+
+    if let Some(ref path) = opts.listeners {
+        info!("Listeners use: {}", path);
+        for listener in file::load_listeners(path)? {
+            let auth = match listener.auth {
+                Some(ref path) => Some(load_auth(
+                    path,
+                    &mut mitm_filters,
+                    &mut user_rules,
+                    &mut users,
+                )?),
+                None => None,
+            };
+            let access_control = AccessControl::new(
+                parse_ip_nets(&listener.allow)?,
+                parse_ip_nets(&listener.deny)?,
+            );
+            listeners.push(
+                Listener::builder()
+                    .addr(
+                        listener
+                            .bind
+                            .parse::<ListenAddr>()
+                            .expect("bind address not valid!"),
+                    )
+                    .mode(listener_mode(&listener)?)
+                    .auth(auth)
+                    .access_control(access_control)
+                    .mitm_filters(listener.mitm_list.map(|l| MitmList::new(l.into_vec())))
+                    .proxy_protocol(listener.proxy_protocol)
+                    .build(),
+            );
+        }
+    }
+
+    if opts.bind.is_some() || listeners.is_empty() {
+        let auth = match opts.auth {
+            Some(ref path) => Some(load_auth(
+                path,
+                &mut mitm_filters,
+                &mut user_rules,
+                &mut users,
+            )?),
+            None => None,
+        };
+        let bind = opts.bind.as_deref().unwrap_or(DEFAULT_BIND);
+        listeners.insert(
+            0,
+            Listener::builder()
+                .addr(bind.parse::<ListenAddr>().expect("bind address not valid!"))
+                .mode(ListenerMode::Proxy)
+                .auth(auth)
+                .access_control(AccessControl::new(opts.allow.clone(), opts.deny.clone()))
+                .build(),
+        );
+    }
+
+    let timeouts = Timeouts {
+        connect: opts.connect_timeout.map(Duration::from_secs),
+        tls_handshake: Some(Duration::from_secs(opts.tls_handshake_timeout)),
+        header_read: opts.header_read_timeout.map(Duration::from_secs),
+        upstream_response: opts.upstream_timeout.map(Duration::from_secs),
+        idle: opts.idle_timeout.map(Duration::from_secs),
+        tunnel: opts.tunnel_timeout.map(Duration::from_secs),
+    };
+
+    // one cache for the MITM list and the ip-cidr filters
+    let resolver = HostResolver::new(timeouts.connect);
+    let mitm_policy = RuleMitmPolicy::new(mitm_filters).with_resolver(resolver.clone());
+    let http_handler = RuleHttpHandler::new(rules)
+        .with_user_rules(user_rules)
+        .with_resolver(resolver.clone());
+    let mut keep_alive = KeepAlive {
+        client: !opts.no_keep_alive,
+        ..Default::default()
+    };
+    if let Some(secs) = opts.pool_idle_timeout {
+        keep_alive.pool_idle_timeout = Some(Duration::from_secs(secs));
+    }
+    if let Some(max) = opts.pool_max_idle {
+        keep_alive.pool_max_idle_per_host = max;
+    }
 
     let proxy = Proxy::builder()
         .ca(ca.clone())
-        .listen_addr(opts.bind.parse().expect("bind address not valid!"))
+        .listeners(listeners)
+        .unix_routes(opts.unix_route.iter().cloned().collect())
+        .send_proxy_protocol(opts.send_proxy_protocol)
+        .timeouts(timeouts)
+        .keep_alive(keep_alive)
+        .passthrough(opts.passthrough_threshold.map(|threshold| {
+            Passthrough::new(threshold, Duration::from_secs(opts.passthrough_cooldown))
+        }))
+        .tls_mirror((!opts.mirror_tls.is_empty()).then(|| TlsMirror::new(opts.mirror_tls.clone())))
         .upstream_proxy(
             opts.proxy
                 .clone()
                 .map(|proxy| hyper_proxy::Proxy::new(Intercept::All, proxy.parse().unwrap())),
         )
         .shutdown_signal(shutdown_signal())
-        .mitm_filters(mitm_filters.clone())
+        .mitm_policy(Arc::new(mitm_policy))
+        .resolver(resolver)
         .handler(http_handler.clone())
         .build();
 
-    tokio::spawn(proxy.start_proxy());
-
-    tokio::signal::ctrl_c()
-        .await
-        .expect("failed to listen for event");
+    // returns on Ctrl+C, or when a listener fails to bind
+    proxy.start_proxy().await?;
     Ok(())
 }
+
+/// User rules are shared by all listeners, so a user in several credentials files must
+/// use the same rules in all of them.
+fn load_auth(
+    path: &str,
+    mitm_filters: &mut Vec<MitmHost>,
+    user_rules: &mut HashMap<String, Arc<Vec<Rule>>>,
+    users: &mut HashMap<String, Option<String>>,
+) -> Result<ProxyAuth> {
+    info!("Proxy auth credentials use: {}", path);
+    let mut auth = ProxyAuth::default();
+    for user in file::load_users(path)? {
+        match users.get(&user.username) {
+            Some(rule) if *rule != user.rule => bail!(
+                "user {} in {} has other rules than in another credentials file",
+                user.username,
+                path
+            ),
+            Some(_) => {}
+            None => {
+                if let Some(ref rule) = user.rule {
+                    let (rules, mut filters) = file::load_rules_amd_mitm_filters(rule)?;
+                    mitm_filters.append(&mut filters);
+                    user_rules.insert(user.username.clone(), Arc::new(rules));
+                }
+                users.insert(user.username.clone(), user.rule);
+            }
+        }
+        auth.add_user(user.username, user.password);
+    }
+    Ok(auth)
+}
+
+fn parse_ip_nets(list: &[String]) -> Result<Vec<IpNet>> {
+    list.iter()
+        .map(|s| parse_ip_net(s).map_err(|err| anyhow::anyhow!("{s}: {err}")))
+        .collect()
+}
+
+fn listener_mode(listener: &flistener::Listener) -> Result<ListenerMode> {
+    let mode = match listener.mode {
+        flistener::Mode::Proxy => ListenerMode::Proxy,
+        flistener::Mode::Transparent => ListenerMode::Transparent,
+        flistener::Mode::Reverse => {
+            let backend = listener.backend.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("reverse listener {} needs backend", listener.bind)
+            })?;
+            let mut reverse = ReverseProxy::new(backend.parse()?)?;
+            if let (Some(cert), Some(key)) = (&listener.cert, &listener.key) {
+                let cert_chain = pemfile::certs(&mut fs::read(cert)?.as_slice())?
+                    .into_iter()
+                    .map(rustls::Certificate)
+                    .collect();
+                let key = pemfile::read_all(&mut fs::read(key)?.as_slice())?
+                    .into_iter()
+                    .find_map(|item| match item {
+                        pemfile::Item::PKCS8Key(key)
+                        | pemfile::Item::RSAKey(key)
+                        | pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+                        _ => None,
+                    })
+                    .ok_or_else(|| anyhow::anyhow!("no private key found in {}", key))?;
+                reverse = reverse.with_cert(cert_chain, key)?;
+            }
+            ListenerMode::Reverse(reverse)
+        }
+    };
+    Ok(mode)
+}